@@ -1,5 +1,7 @@
 use anyhow::{Context, Result, anyhow};
-use std::io::{Read, Seek, Write};
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Seek, Write};
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use tempfile::NamedTempFile;
 
@@ -77,9 +79,17 @@ impl Editor {
     }
 
     pub fn wait(&mut self) -> Result<()> {
+        // hand the terminal to the editor even if our stdin is a pipe (e.g. `cargo build | ge --input -`)
+        let stdin = if std::io::stdin().is_terminal() {
+            Stdio::inherit()
+        } else {
+            File::open("/dev/tty").map_or(Stdio::inherit(), Stdio::from)
+        };
+
         // invoke the actual process here
         let editor = Command::new(&self.args[0])
             .args(&self.args[1..])
+            .stdin(stdin)
             .stdout(Stdio::piped())
             .spawn()
             .with_context(|| format!("failed to start editor {:?}. aborting.", self.args[0]))?;
@@ -90,6 +100,7 @@ impl Editor {
             return Err(anyhow!("editor exited and returned an error. aborting."));
         }
 
+        self.buf.clear();
         if self.read_stdout {
             self.buf.extend_from_slice(&output.stdout);
         } else {
@@ -115,6 +126,43 @@ impl Editor {
     pub fn get_buf(&self) -> &[u8] {
         &self.buf
     }
+
+    pub fn reset(&mut self, content: &[u8]) -> Result<()> {
+        // rewrite the tempfile in place, so that the editor opens the same file again. the content
+        // is the edit result as well until the editor is closed, for retrying without editing
+        self.buf = content.to_vec();
+        let file = self.file.as_file_mut();
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| file.write_all(content))
+            .context("failed to rewrite the tempfile. aborting.")
+    }
+
+    pub fn keep(self) -> Result<PathBuf> {
+        let (_, path) = self
+            .file
+            .keep()
+            .context("failed to keep the tempfile. aborting.")?;
+        Ok(path)
+    }
+}
+
+// asks the user on the terminal, even if our stdin is a pipe
+pub fn prompt(message: &str) -> Result<String> {
+    let mut reader: Box<dyn BufRead> = if std::io::stdin().is_terminal() {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        let tty = File::open("/dev/tty").context("failed to open the terminal. aborting.")?;
+        Box::new(BufReader::new(tty))
+    };
+    eprint!("{message}");
+
+    let mut answer = String::new();
+    let read = reader.read_line(&mut answer);
+    if read.context("failed to read the answer. aborting.")? == 0 {
+        return Err(anyhow!("got EOF from the terminal. aborting."));
+    }
+    Ok(answer.trim().to_string())
 }
 
 impl Write for Editor {
@@ -162,4 +210,17 @@ mod tests {
         editor.wait().unwrap();
         assert_eq!(editor.get_buf(), input.as_bytes());
     }
+
+    #[test]
+    fn test_reset() {
+        let mut editor = Editor::new("touch", false).unwrap();
+        editor.write_all(b"rejected\n").unwrap();
+        editor.wait().unwrap();
+
+        // retrying takes the new content without the editor, and editing again reads it back
+        editor.reset(b"fresh\n").unwrap();
+        assert_eq!(editor.get_buf(), b"fresh\n");
+        editor.wait().unwrap();
+        assert_eq!(editor.get_buf(), b"fresh\n");
+    }
 }
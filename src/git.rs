@@ -1,7 +1,12 @@
+use crate::locations::Location;
 use anyhow::{Context, Result, anyhow};
 use clap::{Parser, ValueEnum};
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
 use std::process::{Command, Stdio};
+use tempfile::NamedTempFile;
 
 pub struct Git;
 
@@ -31,6 +36,15 @@ pub struct GrepOptions {
     )]
     function: bool,
 
+    #[clap(
+        long = "tab-width",
+        value_name = "N",
+        default_value = "4",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Count a tab as advancing to the next multiple of <N> columns in indentation levels"
+    )]
+    tab_width: u32,
+
     #[clap(short = 'i', long = "ignore-case", help = "Case-insensitive search")]
     ignore_case: bool,
 
@@ -61,6 +75,12 @@ pub struct GrepOptions {
     exclude: Vec<String>,
 }
 
+impl GrepOptions {
+    pub fn function_context(&self) -> bool {
+        self.function
+    }
+}
+
 impl Git {
     pub fn new() -> Result<Self> {
         // check the availability of the git command
@@ -73,7 +93,7 @@ impl Git {
         Ok(Git)
     }
 
-    fn expand_options(&self, opts: &GrepOptions, args: &mut Vec<String>) {
+    fn expand_options(&self, opts: &GrepOptions, matches_only: bool, args: &mut Vec<String>) {
         args.push(match opts.mode {
             GrepMode::Fixed => "--fixed-strings".to_string(),
             GrepMode::Basic => "--basic-regexp".to_string(),
@@ -81,7 +101,11 @@ impl Git {
             GrepMode::Pcre => "--perl-regexp".to_string(),
         });
 
-        if opts.function {
+        if matches_only {
+            // the function context is not a match
+            args.push("--only-matching".to_string());
+            args.push("--column".to_string());
+        } else if opts.function {
             args.push("--function-context".to_string());
         }
         if opts.ignore_case {
@@ -95,7 +119,7 @@ impl Git {
         }
     }
 
-    pub fn grep(&self, pattern: &str, merge: bool, opts: &GrepOptions) -> Result<GrepResult> {
+    fn grep_args(&self, pattern: &str, opts: &GrepOptions, matches_only: bool) -> Vec<String> {
         // compose arguments
         let mut args = vec![
             "grep".to_string(),
@@ -105,7 +129,7 @@ impl Git {
             "--null".to_string(), // for unambiguous delimiters
         ];
 
-        self.expand_options(opts, &mut args);
+        self.expand_options(opts, matches_only, &mut args);
         args.push(pattern.to_string());
 
         if !opts.only.is_empty() || !opts.exclude.is_empty() {
@@ -134,23 +158,391 @@ impl Git {
             }
         }
 
+        args
+    }
+
+    fn run_grep(&self, args: &[String]) -> Result<String> {
         // run git-grep then parse the output as a utf-8 string
         let output = Command::new("git")
-            .args(&args)
+            .args(args)
             .output()
             .context("failed to get output of \"git grep\". aborting.")?;
+        String::from_utf8(output.stdout)
+            .context("failed to interpret the output of \"git grep\" as a UTF-8 string. aborting.")
+    }
+
+    pub fn grep(&self, pattern: &str, merge: bool, opts: &GrepOptions) -> Result<GrepResult> {
+        let args = self.grep_args(pattern, opts, false);
+        let output = self.run_grep(&args)?;
+        GrepResult::from_raw(&output, merge, opts.tab_width as usize)
+    }
+
+    // every match as (path, zero-origin line, zero-origin byte column)
+    pub fn grep_matches(
+        &self,
+        pattern: &str,
+        opts: &GrepOptions,
+    ) -> Result<Vec<(String, usize, usize)>> {
+        let args = self.grep_args(pattern, opts, true);
+        let output = self.run_grep(&args)?;
+
+        let mut matches = Vec::new();
+        for line in output.lines() {
+            if line == "--" {
+                continue;
+            }
+
+            // <path> NUL <line> NUL <column> NUL <match>
+            let fields: Vec<_> = line.splitn(4, '\0').collect();
+            let (Some(at), Some(column)) = (fields.get(1), fields.get(2)) else {
+                return Err(anyhow!(
+                    "failed to parse the git-grep match {line:?}. aborting."
+                ));
+            };
+            let at: usize = at
+                .parse()
+                .with_context(|| format!("broken grep line number: {at}. aborting."))?;
+            let column: usize = column
+                .parse()
+                .with_context(|| format!("broken grep column: {column}. aborting."))?;
+            matches.push((fields[0].to_string(), at - 1, column - 1));
+        }
+        Ok(matches)
+    }
+
+    pub fn locate(
+        &self,
+        locations: &[Location],
+        merge: bool,
+        opts: &GrepOptions,
+    ) -> Result<GrepResult> {
+        let mut locations = locations.to_vec();
+        if opts.function {
+            self.extend_to_functions(&mut locations)?;
+        }
+
+        GrepResult::from_locations(&locations, merge, opts.tab_width as usize)
+    }
+
+    fn extend_to_functions(&self, locations: &mut [Location]) -> Result<()> {
+        // locations are grouped by file, so that each file is read and searched once
+        let mut paths: Vec<&str> = Vec::new();
+        let mut by_file: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, location) in locations.iter().enumerate() {
+            let ids = by_file.entry(location.path.as_str()).or_default();
+            if ids.is_empty() {
+                paths.push(location.path.as_str());
+            }
+            ids.push(i);
+        }
+
+        let mut extended = Vec::new();
+        for path in paths {
+            let content = std::fs::read(path).with_context(|| {
+                format!("failed to read {path:?} in the location list. aborting.")
+            })?;
+
+            // the lines are matched as they are, with '\r' in CRLF files
+            let mut lines: Vec<_> = content.split(|&x| x == b'\n').collect();
+            if content.ends_with(b"\n") {
+                lines.pop();
+            }
+
+            // git-grep can't search by line numbers, so we let it find the first and last lines of
+            // the locations by their contents and pick the functions around them by their line
+            // numbers
+            let mut ranges = Vec::new();
+            let mut patterns = Vec::new();
+            for &i in &by_file[path] {
+                let location = &locations[i];
+                let last = location.line + location.n_lines.max(1) - 1;
+                let (Some(head), Some(tail)) = (lines.get(location.line), lines.get(last)) else {
+                    continue;
+                };
+                let (Ok(head), Ok(tail)) = (std::str::from_utf8(head), std::str::from_utf8(tail))
+                else {
+                    continue;
+                };
+                patterns.extend([head, tail]);
+                ranges.push((i, location.line, last));
+            }
+            if ranges.is_empty() {
+                continue;
+            }
+
+            let context = self.function_context(path, &patterns, false)?;
+            if context.is_empty() {
+                eprintln!(
+                    "warning: {path:?} is not tracked by git, so its locations are not extended to the functions."
+                );
+                continue;
+            }
+
+            // the other lines with the same contents may pull in unrelated functions, which are
+            // cut off at the function lines ("=") git marks. the matched lines are marked only
+            // when they aren't matched
+            let inverted = self.function_context(path, &patterns, true)?;
+            let heads: HashSet<_> = context
+                .iter()
+                .chain(&inverted)
+                .filter(|x| x.1 == '=')
+                .map(|x| x.0)
+                .collect();
+
+            for (i, first, last) in ranges {
+                let from = Self::enclosing_function(&context, &heads, first).map_or(first, |x| x.0);
+                let to = Self::enclosing_function(&context, &heads, last).map_or(last + 1, |x| x.1);
+                extended.push((i, from.min(first), to.max(last + 1)));
+            }
+        }
+
+        for (i, from, to) in extended {
+            locations[i].line = from;
+            locations[i].n_lines = to - from;
+        }
+        Ok(())
+    }
+
+    // lines around the ones equal to any of `lines` (or the others with `invert`) with
+    // "--function-context", as the line numbers and the separators git puts after them (':' for
+    // matches, '-' for contexts and '=' for function lines)
+    fn function_context(
+        &self,
+        path: &str,
+        lines: &[&str],
+        invert: bool,
+    ) -> Result<Vec<(usize, char)>> {
+        let mut args = vec![
+            "grep".to_string(),
+            "--color=never".to_string(),
+            "-h".to_string(),
+            "--line-number".to_string(),
+            "--basic-regexp".to_string(),
+            "--function-context".to_string(),
+        ];
+        if invert {
+            args.push("--invert-match".to_string());
+        }
+        for line in lines {
+            let mut pattern = "^".to_string();
+            for c in line.chars() {
+                if matches!(c, '\\' | '.' | '[' | ']' | '*' | '^' | '$') {
+                    pattern.push('\\');
+                }
+                pattern.push(c);
+            }
+            pattern.push('$');
+            args.extend(["-e".to_string(), pattern]);
+        }
+        args.extend(["--".to_string(), format!(":(literal){path}")]);
+
+        let output = Command::new("git")
+            .args(&args)
+            .output()
+            .context("failed to run \"git grep\". aborting.")?;
+        let output = String::from_utf8_lossy(&output.stdout);
+
+        let mut context = vec![];
+        for line in output.lines() {
+            // non-adjacent groups are separated by "--"
+            let Some(end) = line.find([':', '-', '=']) else {
+                continue;
+            };
+            if let Ok(at) = line[..end].parse::<usize>() {
+                context.push((at - 1, line.as_bytes()[end] as char));
+            }
+        }
+        Ok(context)
+    }
+
+    // the range of the function around `at` in the output of `function_context`, given the
+    // function lines
+    fn enclosing_function(
+        context: &[(usize, char)],
+        heads: &HashSet<usize>,
+        at: usize,
+    ) -> Option<(usize, usize)> {
+        let i = context.iter().position(|x| x.0 == at)?;
+        let mut from = i;
+        while from > 0 && context[from - 1].0 + 1 == context[from].0 {
+            from -= 1;
+        }
+        let mut to = i + 1;
+        while to < context.len() && context[to - 1].0 + 1 == context[to].0 {
+            to += 1;
+        }
+        let group = &context[from..to];
+
+        // a lone match is exactly the function of the line; otherwise the group holds the
+        // functions of the other matches, too
+        if group.iter().filter(|x| x.1 == ':').count() == 1 {
+            return Some((group[0].0, group[group.len() - 1].0 + 1));
+        }
+        let start = group[..=i - from]
+            .iter()
+            .rev()
+            .find(|x| heads.contains(&x.0))
+            .unwrap_or(&group[0]);
+        let end = group[i - from + 1..]
+            .iter()
+            .find(|x| heads.contains(&x.0))
+            .map_or(group[group.len() - 1].0 + 1, |x| x.0);
+        Some((start.0, end))
+    }
+
+    // whether git normalizes CRLF in the files to LF when reading them, following core.autocrlf
+    // and the text/eol attributes
+    pub fn converts_crlf(&self, paths: &[&str]) -> Result<Vec<bool>> {
+        if paths.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let output = Command::new("git")
+            .args(["config", "--type=bool-or-str", "--get", "core.autocrlf"])
+            .output()
+            .context("failed to get output of \"git config\". aborting.")?;
+        let autocrlf = matches!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            "true" | "input"
+        );
+
+        let output = Command::new("git")
+            .args(["check-attr", "-z", "text", "eol", "--"])
+            .args(paths)
+            .output()
+            .context("failed to get output of \"git check-attr\". aborting.")?;
         let output = String::from_utf8(output.stdout).context(
-            "failed to interpret the output of \"git grep\" as a UTF-8 string. aborting.",
+            "failed to interpret the output of \"git check-attr\" as a UTF-8 string. aborting.",
         )?;
 
-        GrepResult::from_raw(&output, merge)
+        // <path> NUL <attribute> NUL <value> NUL
+        let fields: Vec<_> = output.split('\0').collect();
+        let attrs: HashMap<_, _> = fields
+            .chunks_exact(3)
+            .map(|x| ((x[0], x[1]), x[2]))
+            .collect();
+
+        let mut converts = Vec::new();
+        for &path in paths {
+            let text = attrs.get(&(path, "text")).copied().unwrap_or("unspecified");
+            let eol = attrs.get(&(path, "eol")).copied().unwrap_or("unspecified");
+
+            // automatic conversion is skipped if the indexed content already has CRLF
+            converts.push(match (text, eol) {
+                ("unset", _) => false,
+                ("auto", _) => !self.has_crlf_in_index(path)?,
+                ("set", _) | (_, "crlf" | "lf") => true,
+                _ => autocrlf && !self.has_crlf_in_index(path)?,
+            });
+        }
+        Ok(converts)
     }
 
-    pub fn apply(&self, patch: &str) -> Result<()> {
-        let mut apply = Command::new("git")
-            .args(["apply", "--unidiff-zero", "-"])
+    fn has_crlf_in_index(&self, path: &str) -> Result<bool> {
+        let output = Command::new("git")
+            .args(["cat-file", "blob", &format!(":./{path}")])
+            .output()
+            .context("failed to get output of \"git cat-file\". aborting.")?;
+
+        // untracked files have nothing in the index
+        Ok(output.status.success() && output.stdout.windows(2).any(|x| x == b"\r\n"))
+    }
+
+    // the author and the date of the latest change in each range of lines
+    pub fn blame(&self, path: &str, ranges: &[Range<usize>]) -> Result<Vec<(String, String)>> {
+        let mut args = vec!["blame".to_string(), "--line-porcelain".to_string()];
+        for range in ranges.iter().filter(|x| !x.is_empty()) {
+            args.push(format!("-L{},{}", range.start + 1, range.end));
+        }
+        args.push("--".to_string());
+        args.push(path.to_string());
+
+        let output = Command::new("git")
+            .args(&args)
+            .output()
+            .context("failed to get output of \"git blame\". aborting.")?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "\"git blame\" failed for {path:?}: {}. aborting.",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let output = String::from_utf8_lossy(&output.stdout);
+
+        // each line starts with "<sha> <original line> <final line>", followed by the headers
+        let mut lines: HashMap<usize, (&str, i64, &str)> = HashMap::new();
+        let (mut at, mut author, mut time) = (0, "", 0);
+        for line in output.lines() {
+            if let Some(x) = line.strip_prefix("author ") {
+                author = x;
+            } else if let Some(x) = line.strip_prefix("author-time ") {
+                time = x.parse().unwrap_or(0);
+            } else if let Some(x) = line.strip_prefix("author-tz ") {
+                lines.insert(at, (author, time, x));
+            } else if !line.starts_with('\t') {
+                let fields: Vec<_> = line.split(' ').collect();
+                if fields.len() >= 3 && fields[0].len() >= 40 {
+                    at = fields[2].parse::<usize>().unwrap_or(1) - 1;
+                }
+            }
+        }
+
+        Ok(ranges
+            .iter()
+            .map(|range| {
+                let latest = range
+                    .clone()
+                    .filter_map(|x| lines.get(&x))
+                    .max_by_key(|x| x.1);
+                latest.map_or((String::new(), String::new()), |(author, time, tz)| {
+                    (author.to_string(), Self::format_date(*time, tz))
+                })
+            })
+            .collect())
+    }
+
+    // YYYY-MM-DD in the timezone given as "+HHMM"
+    fn format_date(time: i64, tz: &str) -> String {
+        let offset = tz.get(1..).and_then(|x| x.parse::<i64>().ok()).unwrap_or(0);
+        let offset = (offset / 100 * 60 + offset % 100) * 60;
+        let days = (time + if tz.starts_with('-') { -offset } else { offset }).div_euclid(86400);
+
+        // civil from days
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let d = doy - (153 * mp + 2) / 5 + 1;
+        let m = if mp < 10 { mp + 3 } else { mp - 9 };
+        let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+        format!("{y:04}-{m:02}-{d:02}")
+    }
+
+    // applies the patch with additional options, returning the output (e.g. of "--stat")
+    pub fn apply(&self, patch: &str, options: &[&str]) -> Result<String> {
+        self.apply_with_index(patch, options, None)
+    }
+
+    // the same as apply, with another index file than ".git/index" if given
+    fn apply_with_index(
+        &self,
+        patch: &str,
+        options: &[&str],
+        index: Option<&Path>,
+    ) -> Result<String> {
+        let mut command = Command::new("git");
+        if let Some(index) = index {
+            command.env("GIT_INDEX_FILE", index);
+        }
+        let mut apply = command
+            .args(["apply", "--unidiff-zero"])
+            .args(options)
+            .arg("-")
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()
             .context("failed to run \"git apply\". aborting.")?;
 
@@ -160,19 +552,172 @@ impl Git {
             stdin.write_all(patch.as_bytes()).unwrap();
         }
 
-        // make sure patch was successful
-        let code = apply
-            .wait()
+        // make sure patch was successful. the messages tell the failed hunks
+        let output = apply
+            .wait_with_output()
             .context("\"git apply\" unexpectedly exited. aborting.")?;
-        if !code.success() {
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!("{}", stderr.trim_end()).context(format!(
+                "\"git apply\" returned an error ({}). aborting.",
+                output.status
+            )));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    // commits only the patch on top of HEAD through a temporary index, leaving out the other
+    // changes in the work tree and the index
+    pub fn commit_patch(&self, patch: &str, message: &str) -> Result<()> {
+        let dir =
+            tempfile::tempdir().context("failed to create a temporary directory. aborting.")?;
+        let index = dir.path().join("index");
+
+        let output = Command::new("git")
+            .env("GIT_INDEX_FILE", &index)
+            .args(["read-tree", "HEAD"])
+            .output()
+            .context("failed to run \"git read-tree\". aborting.")?;
+        if !output.status.success() {
             return Err(anyhow!(
-                "\"git apply\" returned an error{}. aborting.",
-                code
+                "\"git read-tree\" returned an error ({}): {}. aborting.",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
             ));
         }
 
+        self.apply_with_index(patch, &["--cached"], Some(&index))?;
+
+        // hooks and the editor run as usual, while the commit sees the temporary index
+        let status = Command::new("git")
+            .env("GIT_INDEX_FILE", &index)
+            .args(["commit", "--quiet", "--message", message])
+            .status()
+            .context("failed to run \"git commit\". aborting.")?;
+        if !status.success() {
+            return Err(anyhow!(
+                "\"git commit\" returned an error ({status}). aborting."
+            ));
+        }
         Ok(())
     }
+
+    // merges the changes from the base to the edited content into the current content of the
+    // file, returning the result with the number of conflicts
+    pub fn merge_file(&self, path: &str, base: &[u8], edited: &[u8]) -> Result<(Vec<u8>, usize)> {
+        let mut files = Vec::new();
+        for content in [base, edited] {
+            let mut file = NamedTempFile::new().context("failed to create tempfile. aborting.")?;
+            file.write_all(content)
+                .context("failed to write the tempfile. aborting.")?;
+            files.push(file);
+        }
+
+        let output = Command::new("git")
+            .args([
+                "merge-file",
+                "-p",
+                "-L",
+                "current",
+                "-L",
+                "snapshot",
+                "-L",
+                "edited",
+            ])
+            .arg(path)
+            .args(files.iter().map(|x| x.path()))
+            .output()
+            .context("failed to run \"git merge-file\". aborting.")?;
+
+        // the exit status is the number of conflicts, or negative on errors
+        match output.status.code() {
+            Some(n) if (0..128).contains(&n) => Ok((output.stdout, n as usize)),
+            _ => Err(anyhow!(
+                "\"git merge-file\" returned an error ({}). aborting.",
+                output.status
+            )),
+        }
+    }
+
+    // the funcnames of hunks at the zero-origin positions in the file, as "git diff" puts in the
+    // hunk headers with the diff driver of the path. marker lines are inserted at the positions
+    // and compared with the file, so that git finds the function lines before them
+    pub fn funcnames(
+        &self,
+        path: &str,
+        content: &[u8],
+        positions: &[usize],
+    ) -> Result<Vec<Option<String>>> {
+        let lines: Vec<_> = content.split_inclusive(|&x| x == b'\n').collect();
+        let mut marker = b"ge:funcname".to_vec();
+        while lines.iter().any(|x| x.trim_ascii_end() == marker) {
+            marker.push(b'_');
+        }
+        marker.push(b'\n');
+
+        // a marker after the last line without the newline would change the line, so the positions
+        // after it are taken as the line
+        let end = lines.len() - usize::from(!content.is_empty() && !content.ends_with(b"\n"));
+        let mut sorted: Vec<_> = positions.iter().map(|&x| x.min(end)).collect();
+        sorted.sort();
+        sorted.dedup();
+        let mut marked = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            if sorted.binary_search(&i).is_ok() {
+                marked.extend_from_slice(&marker);
+            }
+            marked.extend_from_slice(line);
+        }
+        if sorted.last() == Some(&lines.len()) {
+            marked.extend_from_slice(&marker);
+        }
+
+        let mut file = NamedTempFile::new().context("failed to create tempfile. aborting.")?;
+        file.write_all(&marked)
+            .context("failed to write the tempfile. aborting.")?;
+        let output = Command::new("git")
+            .args([
+                "diff",
+                "--no-index",
+                "--no-ext-diff",
+                "--no-color",
+                "--text",
+                "--unified=0",
+                "--inter-hunk-context=0",
+                "--",
+            ])
+            .arg(path)
+            .arg(file.path())
+            .output()
+            .context("failed to run \"git diff\". aborting.")?;
+
+        // the exit status is 1 when the files differ
+        if output.status.code() != Some(1) {
+            return Err(anyhow!(
+                "\"git diff\" returned an error ({}). aborting.",
+                output.status
+            ));
+        }
+        let headers: Vec<_> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|x| x.starts_with("@@ -"))
+            .map(|x| {
+                let funcname = x.splitn(3, "@@").nth(2).unwrap_or("").trim();
+                (!funcname.is_empty()).then(|| funcname.to_string())
+            })
+            .collect();
+        if headers.len() != sorted.len() {
+            return Err(anyhow!(
+                "failed to find the funcnames in {path:?}. aborting."
+            ));
+        }
+
+        Ok(positions
+            .iter()
+            .map(|&x| headers[sorted.binary_search(&x.min(end)).unwrap()].clone())
+            .collect())
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -180,7 +725,7 @@ pub struct GrepHit {
     pub file_id: usize,
     pub from: usize,
     pub n_lines: usize,
-    pub level: usize, // the number of leading space and tabs of the line
+    pub level: usize, // the indentation width (in columns) of the line
 }
 
 #[derive(Debug)]
@@ -190,7 +735,20 @@ pub struct GrepResult {
 }
 
 impl GrepResult {
-    fn parse_line(line: &str) -> Result<(&str, usize, usize)> {
+    pub fn indent_level(line: &str, tab_width: usize) -> usize {
+        // tabs advance to the next tab stop; other whitespaces count as one column
+        let mut level = 0;
+        for c in line.chars() {
+            match c {
+                '\t' => level += tab_width - level % tab_width,
+                c if c.is_whitespace() => level += 1,
+                _ => break,
+            }
+        }
+        level
+    }
+
+    fn parse_line(line: &str, tab_width: usize) -> Result<(&str, usize, usize)> {
         // find two '\0's
         let pos = line.find('\0').with_context(|| {
             format!("failed to find filename-linenumber delimiter in {line:?}. aborting.")
@@ -207,60 +765,188 @@ impl GrepResult {
             .with_context(|| format!("broken grep line number: {at}. aborting."))?;
         debug_assert!(at > 0);
 
-        // the number of leading space and tabs of the line
-        let line = &line[1..];
-        let level = line.len() - line.trim_start().len();
+        // the indentation width of the line
+        let level = Self::indent_level(&line[1..], tab_width);
 
         Ok((filename, at - 1, level))
     }
 
-    fn from_raw(raw: &str, merge: bool) -> Result<GrepResult> {
-        let mut bin = GrepResult {
-            files: Vec::new(),
-            hits: Vec::new(),
-        };
-
+    fn from_raw(raw: &str, merge: bool, tab_width: usize) -> Result<GrepResult> {
         let parse = |line| {
             if line == "--" {
                 return None;
             }
-            let ret = Self::parse_line(line).unwrap();
-            Some(ret)
+            let (filename, at, level) = Self::parse_line(line, tab_width).unwrap();
+            Some((filename, at, 1, level))
+        };
+        let lines: Vec<_> = raw.trim().lines().filter_map(parse).collect();
+
+        Ok(Self::from_entries(lines, merge))
+    }
+
+    fn from_locations(locations: &[Location], merge: bool, tab_width: usize) -> Result<GrepResult> {
+        // locations don't carry the line contents, so we read the files to compute indentation levels
+        let mut levels: HashMap<&str, Vec<usize>> = HashMap::new();
+        for location in locations {
+            let path = location.path.as_str();
+            if levels.contains_key(path) {
+                continue;
+            }
+
+            let content = std::fs::read_to_string(path).with_context(|| {
+                format!("failed to read {path:?} in the location list. aborting.")
+            })?;
+            levels.insert(
+                path,
+                content
+                    .lines()
+                    .map(|x| Self::indent_level(x, tab_width))
+                    .collect(),
+            );
+        }
+
+        let entries = locations
+            .iter()
+            .map(|x| {
+                let path = x.path.as_str();
+                let level = levels[path].get(x.line).copied().unwrap_or(0);
+                (path, x.line, x.n_lines, level)
+            })
+            .collect();
+
+        Ok(Self::from_entries(entries, merge))
+    }
+
+    fn from_entries(mut entries: Vec<(&str, usize, usize, usize)>, merge: bool) -> GrepResult {
+        let mut bin = GrepResult {
+            files: Vec::new(),
+            hits: Vec::new(),
         };
-        let mut lines: Vec<_> = raw.trim().lines().filter_map(parse).collect();
 
         // sort by (filename, linenumber) tuple so that filenames are in the dictionary ascending order
-        lines.sort();
+        entries.sort();
+        entries.dedup();
 
-        for (filename, at, level) in lines {
+        for (filename, at, n_lines, level) in entries {
             if bin.files.is_empty() || bin.files.last().unwrap() != filename {
                 bin.files.push(filename.to_string());
             }
 
-            let file_id = bin.files.len() - 1;
-            if merge && bin.hits.last_mut().is_some() {
-                let last_hit = bin.hits.last_mut().unwrap();
-                if last_hit.file_id == file_id && last_hit.from + last_hit.n_lines == at {
-                    last_hit.n_lines += 1;
-                    continue;
-                }
-            }
-
             bin.hits.push(GrepHit {
-                file_id,
+                file_id: bin.files.len() - 1,
                 from: at,
-                n_lines: 1,
+                n_lines,
                 level,
             });
         }
-        Ok(bin)
+
+        if merge {
+            bin.merge_hits();
+        }
+        bin
+    }
+
+    // merges overlapping and adjacent hits, which must be sorted
+    pub fn merge_hits(&mut self) {
+        let mut merged: Vec<GrepHit> = Vec::new();
+        for hit in self.hits.drain(..) {
+            if let Some(last) = merged.last_mut()
+                && last.file_id == hit.file_id
+                && last.from + last.n_lines >= hit.from
+            {
+                last.n_lines = last.n_lines.max(hit.from + hit.n_lines - last.from);
+                continue;
+            }
+            merged.push(hit);
+        }
+        self.hits = merged;
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::git::GrepResult;
     use crate::{Git, GrepOptions};
     use clap::Parser;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_indent_level() {
+        assert_eq!(GrepResult::indent_level("foo", 4), 0);
+        assert_eq!(GrepResult::indent_level("    foo", 4), 4);
+        assert_eq!(GrepResult::indent_level("\tfoo", 4), 4);
+        assert_eq!(GrepResult::indent_level("\tfoo", 8), 8);
+        assert_eq!(GrepResult::indent_level("  \tfoo", 4), 4);
+        assert_eq!(GrepResult::indent_level("\t  foo", 4), 6);
+        assert_eq!(GrepResult::indent_level("\t\tfoo", 1), 2);
+        assert_eq!(GrepResult::indent_level("    ", 4), 4);
+        assert_eq!(GrepResult::indent_level("\u{3000}\tfoo", 4), 4);
+    }
+
+    #[test]
+    fn test_enclosing_function() {
+        // fn a() { <blank> } <blank> fn b() { <blank> }, searching blank lines
+        let context = [
+            (0, '='),
+            (1, ':'),
+            (2, '-'),
+            (3, ':'),
+            (4, '='),
+            (5, ':'),
+            (6, '-'),
+        ];
+        let heads = HashSet::from([0, 4]);
+        assert_eq!(Git::enclosing_function(&context, &heads, 1), Some((0, 4)));
+        assert_eq!(Git::enclosing_function(&context, &heads, 5), Some((4, 7)));
+        assert_eq!(Git::enclosing_function(&context, &heads, 2), Some((0, 4)));
+        assert_eq!(Git::enclosing_function(&context, &heads, 7), None);
+
+        // matched function lines split the group as well
+        let context = [(0, ':'), (1, '-'), (2, ':'), (3, ':'), (4, '-')];
+        let heads = HashSet::from([0, 2]);
+        assert_eq!(Git::enclosing_function(&context, &heads, 3), Some((2, 5)));
+        assert_eq!(Git::enclosing_function(&context, &heads, 0), Some((0, 2)));
+
+        // a lone match, and a gap to the next group
+        let context = [(2, '-'), (3, '='), (4, ':'), (5, '-'), (9, '='), (10, ':')];
+        let heads = HashSet::from([3, 9]);
+        assert_eq!(Git::enclosing_function(&context, &heads, 4), Some((2, 6)));
+        assert_eq!(Git::enclosing_function(&context, &heads, 10), Some((9, 11)));
+    }
+
+    #[test]
+    fn test_funcnames() {
+        // the default driver of git, for a file without attributes
+        let git = Git::new().unwrap();
+        let path = std::env::temp_dir().join(format!("ge-funcnames-{}.txt", std::process::id()));
+        let content = format!(
+            "impl Foo {{\n    fn foo() {{\n\n#[test]\n$bar = 1;\n    baz();\n{}\nqux\n",
+            "x".repeat(100)
+        );
+        std::fs::write(&path, &content).unwrap();
+        let funcnames = git
+            .funcnames(
+                path.to_str().unwrap(),
+                content.as_bytes(),
+                &[6, 0, 3, 5, 3, 7, 8],
+            )
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let foo = Some("impl Foo {".to_string());
+        let bar = Some("$bar = 1;".to_string());
+        assert_eq!(funcnames[..5], [bar.clone(), None, foo.clone(), bar, foo]);
+        assert_eq!(funcnames[5].as_ref().unwrap().len(), 80);
+        assert_eq!(funcnames[6], Some("qux".to_string()));
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(Git::format_date(0, "+0000"), "1970-01-01");
+        assert_eq!(Git::format_date(1709251199, "+0000"), "2024-02-29");
+        assert_eq!(Git::format_date(1709251199, "+0900"), "2024-03-01");
+        assert_eq!(Git::format_date(1709254800, "-0130"), "2024-02-29");
+    }
 
     #[test]
     fn test_new() {
@@ -1,10 +1,28 @@
-use crate::git::{Git, GrepOptions, GrepResult};
-use anyhow::Result;
-use clap::Parser;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use crate::git::{Git, GrepHit, GrepOptions, GrepResult};
+use crate::lexer::{self, Class};
+use crate::locations::{Location, Replacement};
+use anyhow::{Context, Result, anyhow};
+use clap::{Parser, ValueEnum};
+use std::collections::{HashMap, HashSet};
 use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum LevelPolicy {
+    Same,
+    Outer,
+    Any,
+}
+
+impl LevelPolicy {
+    fn accepts(self, hit: usize, to: usize) -> bool {
+        match self {
+            LevelPolicy::Same => to == hit,
+            LevelPolicy::Outer => to < hit,
+            LevelPolicy::Any => true,
+        }
+    }
+}
 
 #[derive(Debug, Parser)]
 pub struct HunkOptions {
@@ -32,6 +50,14 @@ pub struct HunkOptions {
     )]
     after: Option<usize>,
 
+    #[clap(
+        long = "show-context",
+        value_name = "N",
+        default_value = "0",
+        help = "Show <N> read-only lines before and after hunks"
+    )]
+    show_context: usize,
+
     #[clap(
         short = 'H',
         long = "head",
@@ -54,20 +80,59 @@ pub struct HunkOptions {
     )]
     without: Option<String>,
 
+    #[clap(
+        long = "code-only",
+        conflicts_with_all = ["comments_only", "strings_only"],
+        help = "Keep hits in code, excluding comments and string literals"
+    )]
+    code_only: bool,
+
+    #[clap(
+        long = "comments-only",
+        conflicts_with = "strings_only",
+        help = "Keep hits in comments"
+    )]
+    comments_only: bool,
+
+    #[clap(long = "strings-only", help = "Keep hits in string literals")]
+    strings_only: bool,
+
     #[clap(
         long = "to",
         value_name = "PATTERN",
-        help = "Extend match downward until the first hit of PATTERN with the same indentation level"
+        help = "Extend match downward until the first hit of PATTERN at the indentation level given by --to-level"
     )]
     to: Option<String>,
+
+    #[clap(
+        value_enum,
+        long = "to-level",
+        default_value = "same",
+        help = "Indentation level of the --to PATTERN hit relative to the match"
+    )]
+    to_level: LevelPolicy,
+
+    #[clap(
+        long = "no-merge",
+        conflicts_with = "merge_gap",
+        help = "Keep adjacent matches in separate hunks (overlapping ones are still merged)"
+    )]
+    no_merge: bool,
+
+    #[clap(
+        long = "merge-gap",
+        value_name = "N",
+        help = "Merge hunks separated by <N> or fewer lines [default: 0]"
+    )]
+    merge_gap: Option<usize>,
 }
 
 trait MatchExtender {
     fn filter_files(&mut self, secondary: &GrepResult, invert: bool) -> Result<()>;
     fn collect_head(&mut self, n_lines: usize) -> Result<()>;
-    fn extend_to_another(&mut self, to: &GrepResult) -> Result<()>;
+    fn extend_to_another(&mut self, to: &GrepResult, policy: LevelPolicy) -> Result<()>;
     fn extend_by_lines(&mut self, up: usize, down: usize) -> Result<()>;
-    fn filter_overlaps(&mut self) -> Result<()>;
+    fn filter_overlaps(&mut self, gap: Option<usize>) -> Result<()>;
 }
 
 impl MatchExtender for GrepResult {
@@ -100,32 +165,30 @@ impl MatchExtender for GrepResult {
         Ok(())
     }
 
-    fn extend_to_another(&mut self, to: &GrepResult) -> Result<()> {
-        let mut it = to.hits.iter().peekable();
+    fn extend_to_another(&mut self, to: &GrepResult, policy: LevelPolicy) -> Result<()> {
+        let mut start = 0;
 
         for hit in &mut self.hits {
             // skip_while
             // note: files (filenames) are sorted in the ascending order so it's safe to
-            // iterate this loop with the >= comparator.
-            while let Some(x) = it.peek() {
-                if (&to.files[x.file_id], x.from) >= (&self.files[hit.file_id], hit.from)
-                    && x.level == hit.level
-                {
+            // advance the head with the < comparator.
+            let filename = &self.files[hit.file_id];
+            while let Some(x) = to.hits.get(start) {
+                if (&to.files[x.file_id], x.from) >= (filename, hit.from) {
                     break;
                 }
-                it.next().unwrap();
+                start += 1;
             }
 
-            if it.peek().is_none() {
-                break;
-            }
-            let next = it.peek().unwrap();
-            if to.files[next.file_id] != self.files[hit.file_id] {
-                continue;
-            }
+            // the first hit in the same file at an acceptable indentation level
+            let next = to.hits[start..]
+                .iter()
+                .take_while(|x| &to.files[x.file_id] == filename)
+                .find(|x| policy.accepts(hit.level, x.level));
 
-            let next = it.next().unwrap();
-            hit.n_lines = next.from + next.n_lines - hit.from;
+            if let Some(next) = next {
+                hit.n_lines = hit.n_lines.max(next.from + next.n_lines - hit.from);
+            }
         }
         Ok(())
     }
@@ -141,7 +204,17 @@ impl MatchExtender for GrepResult {
         Ok(())
     }
 
-    fn filter_overlaps(&mut self) -> Result<()> {
+    fn filter_overlaps(&mut self, gap: Option<usize>) -> Result<()> {
+        // hunks that overlap are always merged as PatchBuilder can't handle them. adjacent ones
+        // (and ones separated by less than or equal to `gap` lines) are merged if `gap` is given.
+        let mergeable = |dst: &GrepHit, src: &GrepHit| {
+            let end = dst.from + dst.n_lines;
+            match gap {
+                Some(gap) => end + gap >= src.from,
+                None => end > src.from,
+            }
+        };
+
         let mut n_drop = 0;
         for i in 1..self.hits.len() {
             let (dst, srcs) = self.hits.split_at_mut(i - n_drop);
@@ -149,8 +222,8 @@ impl MatchExtender for GrepResult {
             let dst = dst.last_mut().unwrap();
             let src = &srcs[n_drop];
 
-            if dst.file_id == src.file_id && dst.from + dst.n_lines >= src.from {
-                dst.n_lines = src.from + src.n_lines - dst.from;
+            if dst.file_id == src.file_id && mergeable(dst, src) {
+                dst.n_lines = dst.n_lines.max(src.from + src.n_lines - dst.from);
                 n_drop += 1;
             }
 
@@ -164,31 +237,195 @@ impl MatchExtender for GrepResult {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Annotation {
+    Funcname,
+    Blame,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    // the terminator of the first line decides, as editors do
+    pub fn detect(content: &[u8]) -> Self {
+        match content.iter().position(|&x| x == b'\n') {
+            Some(i) if i > 0 && content[i - 1] == b'\r' => LineEnding::Crlf,
+            _ => LineEnding::Lf,
+        }
+    }
+
+    // strips the terminator of a line. only CRLF files lose the '\r', as a stray one in an LF
+    // file is part of the line
+    pub fn strip(self, line: &[u8]) -> &[u8] {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        match self {
+            LineEnding::Lf => line,
+            LineEnding::Crlf => line.strip_suffix(b"\r").unwrap_or(line),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+}
+
+// fingerprint of a file, to detect changes made while editing. FNV-1a is used as the hashes are
+// saved in snapshots, which must be read by other builds
+pub fn hash_content(content: &[u8]) -> u64 {
+    content.iter().fold(0xcbf29ce484222325, |acc, &x| {
+        (acc ^ x as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// each worker takes the next item until all are taken, and the results are put back in the
+// original order so that the output is deterministic
+fn parallel_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let n_workers = std::thread::available_parallelism()
+        .map_or(1, |x| x.get())
+        .min(items.len());
+
+    let mut results: Vec<_> = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..n_workers)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut acc = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            break acc;
+                        };
+                        acc.push((i, f(item)));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|x| x.join().unwrap())
+            .collect()
+    });
+    results.sort_by_key(|x| x.0);
+    results.into_iter().map(|x| x.1).collect()
+}
+
+// hunks read from a file, with the final newline state of the one reaching the end of file
+struct FileHunks {
+    id: usize,
+    line_ending: LineEnding,
+    hash: u64,
+    hunks: Vec<(usize, Vec<String>, Option<bool>)>,
+    context: Vec<(Vec<String>, Vec<String>)>,
+}
+
 #[derive(Debug)]
 pub struct Hunks {
     pub files: Vec<String>,
     pub hunks: Vec<(usize, usize, Vec<String>)>,
+    pub prefill: HashMap<(usize, usize), Vec<String>>, // lines shown in place of the originals
+    pub line_endings: Vec<LineEnding>,                 // terminators of the patch lines, per file
+    pub hashes: Vec<Option<u64>>, // content hashes at collection, per file read
+    pub eof_newline: HashMap<(usize, usize), bool>, // whether hunks reaching the end of file end with a newline
+    pub context: HashMap<(usize, usize), (Vec<String>, Vec<String>)>, // read-only lines around hunks
+    pub annotations: HashMap<(usize, usize), Vec<String>>, // shown after the hunk markers
 }
 
 impl Hunks {
-    pub fn collect(
+    pub fn search(
         git: &Git,
         pattern: &str,
         grep_opts: &GrepOptions,
         hunk_opts: &HunkOptions,
+    ) -> Result<GrepResult> {
+        let merge = !hunk_opts.no_merge;
+        let class = if hunk_opts.code_only {
+            Class::Code
+        } else if hunk_opts.comments_only {
+            Class::Comment
+        } else if hunk_opts.strings_only {
+            Class::String
+        } else {
+            return git.grep(pattern, merge, grep_opts);
+        };
+
+        // lines that have a match in the class
+        let positions = git.grep_matches(pattern, grep_opts)?;
+        let classes = lexer::classify_matches(&positions)?;
+        let lines: HashSet<_> = positions
+            .iter()
+            .zip(classes)
+            .filter(|x| x.1 == class)
+            .map(|x| (x.0.0.as_str(), x.0.1))
+            .collect();
+
+        if grep_opts.function_context() {
+            // functions are kept as a whole if they have any of the lines
+            let mut matches = git.grep(pattern, merge, grep_opts)?;
+            let files = &matches.files;
+            matches.hits.retain(|x| {
+                (x.from..x.from + x.n_lines)
+                    .any(|y| lines.contains(&(files[x.file_id].as_str(), y)))
+            });
+            Ok(matches)
+        } else {
+            // lines are filtered before merged, so that comments next to code are split off
+            let mut matches = git.grep(pattern, false, grep_opts)?;
+            let files = &matches.files;
+            matches
+                .hits
+                .retain(|x| lines.contains(&(files[x.file_id].as_str(), x.from)));
+            if merge {
+                matches.merge_hits();
+            }
+            Ok(matches)
+        }
+    }
+
+    pub fn locate(
+        git: &Git,
+        locations: &[Location],
+        grep_opts: &GrepOptions,
+        hunk_opts: &HunkOptions,
+    ) -> Result<GrepResult> {
+        git.locate(locations, !hunk_opts.no_merge, grep_opts)
+    }
+
+    pub fn collect(
+        git: &Git,
+        matches: GrepResult,
+        grep_opts: &GrepOptions,
+        hunk_opts: &HunkOptions,
     ) -> Result<Self> {
-        let matches = Self::collect_matches(git, pattern, grep_opts, hunk_opts)?;
-        Self::collect_hunks(matches)
+        let matches = Self::collect_matches(git, matches, grep_opts, hunk_opts)?;
+        let mut hunks = Self::collect_hunks(matches, hunk_opts.show_context)?;
+
+        // git applies patches to the content normalized to LF if it converts the line endings
+        let crlf: Vec<_> = (0..hunks.files.len())
+            .filter(|x| hunks.line_endings[*x] == LineEnding::Crlf)
+            .collect();
+        let paths: Vec<_> = crlf.iter().map(|x| hunks.files[*x].as_str()).collect();
+        for (id, converts) in crlf.iter().zip(git.converts_crlf(&paths)?) {
+            if converts {
+                hunks.line_endings[*id] = LineEnding::Lf;
+            }
+        }
+        Ok(hunks)
     }
 
     fn collect_matches(
         git: &Git,
-        pattern: &str,
+        mut matches: GrepResult,
         grep_opts: &GrepOptions,
         hunk_opts: &HunkOptions,
     ) -> Result<GrepResult> {
-        let mut matches = git.grep(pattern, true, grep_opts)?;
-
         // first filter files out
         if let Some(pattern) = &hunk_opts.with {
             let with = git.grep(pattern, false, grep_opts)?;
@@ -208,7 +445,7 @@ impl Hunks {
         // extend to secondary hit locations
         if let Some(pattern) = &hunk_opts.to {
             let to = git.grep(pattern, false, grep_opts)?;
-            matches.extend_to_another(&to)?;
+            matches.extend_to_another(&to, hunk_opts.to_level)?;
         }
 
         // lastly extend hits upward and downward
@@ -222,87 +459,287 @@ impl Hunks {
             }
         }
 
-        matches.filter_overlaps()?;
+        let gap = if hunk_opts.no_merge {
+            None
+        } else {
+            Some(hunk_opts.merge_gap.unwrap_or(0))
+        };
+        matches.filter_overlaps(gap)?;
 
         Ok(matches)
     }
 
-    fn collect_hunks(matches: GrepResult) -> Result<Self> {
-        let mut hunks = Vec::new();
-
+    fn collect_hunks(matches: GrepResult, show_context: usize) -> Result<Self> {
         // group_by iterator
+        let mut ranges = Vec::new();
         let mut from = 0;
         for i in 1..matches.hits.len() {
-            let (first, next) = matches.hits.split_at(i);
-            let first = &first[from];
-            let next = &next[0];
-
-            if first.file_id == next.file_id {
-                continue;
+            if matches.hits[from].file_id != matches.hits[i].file_id {
+                ranges.push(from..i);
+                from = i;
             }
-
-            Self::collect_hunks_from_file(&matches, from..i, &mut hunks)?;
-            from = i;
         }
-
         if from < matches.hits.len() {
-            Self::collect_hunks_from_file(&matches, from..matches.hits.len(), &mut hunks)?;
+            ranges.push(from..matches.hits.len());
         }
 
-        Ok(Hunks {
-            files: matches.files,
-            hunks,
-        })
+        let results = parallel_map(&ranges, |range| {
+            Self::collect_hunks_from_file(&matches, range.clone(), show_context)
+        });
+
+        let mut acc = Hunks {
+            files: Vec::new(),
+            hunks: Vec::new(),
+            prefill: HashMap::new(),
+            line_endings: vec![LineEnding::Lf; matches.files.len()],
+            hashes: vec![None; matches.files.len()],
+            eof_newline: HashMap::new(),
+            context: HashMap::new(),
+            annotations: HashMap::new(),
+        };
+        for result in results {
+            let file = result?;
+            acc.line_endings[file.id] = file.line_ending;
+            acc.hashes[file.id] = Some(file.hash);
+            for ((pos, lines, eof_newline), context) in file.hunks.into_iter().zip(file.context) {
+                if let Some(eof_newline) = eof_newline {
+                    acc.eof_newline.insert((file.id, pos), eof_newline);
+                }
+                if !context.0.is_empty() || !context.1.is_empty() {
+                    acc.context.insert((file.id, pos), context);
+                }
+                acc.hunks.push((file.id, pos, lines));
+            }
+        }
+
+        acc.files = matches.files;
+        Ok(acc)
     }
 
-    fn collect_hunks_from_file(
-        matches: &GrepResult,
-        range: Range<usize>,
-        hunks: &mut Vec<(usize, usize, Vec<String>)>,
-    ) -> Result<()> {
-        let file_id = matches.hits[range.start].file_id;
-        let f = BufReader::new(File::open(&matches.files[file_id])?);
+    pub fn annotate(&mut self, git: &Git, kinds: &[Annotation]) -> Result<()> {
+        if kinds.is_empty() {
+            return Ok(());
+        }
 
-        let mut it = f.lines().enumerate().peekable();
+        let mut by_file: Vec<(usize, Vec<(usize, usize)>)> = Vec::new();
+        for (id, pos, lines) in &self.hunks {
+            if by_file.last().is_none_or(|x| x.0 != *id) {
+                by_file.push((*id, Vec::new()));
+            }
+            by_file.last_mut().unwrap().1.push((*pos, lines.len()));
+        }
 
-        for hit in &matches.hits[range] {
-            // skip_while
-            while let Some(&(x, _)) = it.peek() {
-                if x >= hit.from {
-                    break;
+        let results = parallel_map(&by_file, |(id, hunks)| -> Result<Vec<Vec<String>>> {
+            let path = &self.files[*id];
+            let mut annotations = vec![Vec::new(); hunks.len()];
+
+            for kind in kinds {
+                match kind {
+                    Annotation::Funcname => {
+                        let content = std::fs::read(path)
+                            .with_context(|| format!("failed to read {path:?}. aborting."))?;
+                        let positions: Vec<_> = hunks.iter().map(|x| x.0).collect();
+                        for (funcname, acc) in git
+                            .funcnames(path, &content, &positions)?
+                            .into_iter()
+                            .zip(&mut annotations)
+                        {
+                            acc.extend(funcname);
+                        }
+                    }
+                    Annotation::Blame => {
+                        let ranges: Vec<_> = hunks.iter().map(|(pos, n)| *pos..pos + n).collect();
+                        for ((author, date), acc) in
+                            git.blame(path, &ranges)?.into_iter().zip(&mut annotations)
+                        {
+                            if !author.is_empty() {
+                                acc.push(format!("{author} {date}"));
+                            }
+                        }
+                    }
                 }
-                it.next().unwrap().1?;
             }
+            Ok(annotations)
+        });
 
-            let lines = Self::collect_lines(&mut it, hit.n_lines)?;
-            hunks.push((file_id, hit.from, lines));
+        for ((id, hunks), result) in by_file.iter().zip(results) {
+            for ((pos, _), annotations) in hunks.iter().zip(result?) {
+                if !annotations.is_empty() {
+                    self.annotations.insert((*id, *pos), annotations);
+                }
+            }
         }
-
         Ok(())
     }
 
-    fn collect_lines<I>(it: &mut I, n_lines: usize) -> Result<Vec<String>>
-    where
-        I: Iterator<Item = (usize, Result<std::string::String, std::io::Error>)>,
-    {
-        let mut acc = Vec::new();
+    pub fn apply_replacements(&mut self, replacements: &[Replacement]) -> Result<()> {
+        for (file_id, pos, lines) in &self.hunks {
+            let filename = &self.files[*file_id];
+            let end = pos + lines.len();
+
+            // replacements that fit in the hunk. the end may point at the head of the next line
+            let mut targets: Vec<_> = replacements
+                .iter()
+                .filter(|x| {
+                    &x.path == filename && x.start.0 >= *pos && (x.end.0 < end || x.end == (end, 0))
+                })
+                .collect();
+            if targets.is_empty() {
+                continue;
+            }
+
+            // we take the first one if some suggestions overlap
+            targets.sort_by_key(|x| (x.start, x.end));
+            let mut last_end = (0, 0);
+            targets.retain(|x| {
+                let keep = x.start >= last_end;
+                if keep {
+                    last_end = x.end;
+                }
+                keep
+            });
+
+            // byte offset of (line, column) in the concatenated lines
+            let offset = |(line, col): (usize, usize)| -> usize {
+                let line = line - pos;
+                let head: usize = lines[..line.min(lines.len())]
+                    .iter()
+                    .map(|x| x.len() + 1)
+                    .sum();
+                let col = lines
+                    .get(line)
+                    .map_or(0, |x| x.char_indices().nth(col).map_or(x.len(), |x| x.0));
+                head + col
+            };
+
+            let mut text: String = lines.iter().map(|x| format!("{x}\n")).collect();
+            for x in targets.iter().rev() {
+                text.replace_range(offset(x.start)..offset(x.end), &x.text);
+            }
 
-        for _ in 0..n_lines {
-            if let Some((_, line)) = it.next() {
-                acc.push(line?.to_string());
+            let text = text.strip_suffix('\n').unwrap_or(&text);
+            let edited: Vec<_> = if text.is_empty() {
+                Vec::new()
             } else {
-                break;
+                text.split('\n').map(|x| x.to_string()).collect()
+            };
+            if &edited != lines {
+                self.prefill.insert((*file_id, *pos), edited);
             }
         }
+        Ok(())
+    }
 
-        Ok(acc)
+    fn collect_hunks_from_file(
+        matches: &GrepResult,
+        range: Range<usize>,
+        show_context: usize,
+    ) -> Result<FileHunks> {
+        let id = matches.hits[range.start].file_id;
+        let filename = &matches.files[id];
+        let content = std::fs::read(filename)
+            .with_context(|| format!("failed to read {filename:?}. aborting."))?;
+
+        // byte offsets of the line heads, followed by the end of the content. the last line may
+        // lack the terminator
+        let mut offsets: Vec<_> = std::iter::once(0)
+            .chain(
+                content
+                    .iter()
+                    .enumerate()
+                    .filter(|x| *x.1 == b'\n')
+                    .map(|x| x.0 + 1),
+            )
+            .collect();
+        if !content.ends_with(b"\n") {
+            offsets.push(content.len());
+        }
+        let n_lines = offsets.len() - 1;
+        let has_newline = content.ends_with(b"\n");
+        let line_ending = LineEnding::detect(&content);
+
+        // only the lines in the hunks are decoded. the edited lines are written back with the
+        // line ending of the file, so LF lines in a CRLF file would be changed
+        let read_lines = |lines: Range<usize>| -> Result<Vec<String>> {
+            let mut acc = Vec::new();
+            for i in lines.start..lines.end.min(n_lines) {
+                let line = &content[offsets[i]..offsets[i + 1]];
+                if line_ending == LineEnding::Crlf
+                    && line.ends_with(b"\n")
+                    && !line.ends_with(b"\r\n")
+                {
+                    return Err(anyhow!(
+                        "{filename:?} has mixed line endings around line {}. aborting.",
+                        i + 1
+                    ));
+                }
+                acc.push(std::str::from_utf8(line_ending.strip(line))?.to_string());
+            }
+            Ok(acc)
+        };
+
+        let hits = &matches.hits[range];
+        let mut hunks = Vec::new();
+        let mut context = Vec::new();
+        for (i, hit) in hits.iter().enumerate() {
+            let to = (hit.from + hit.n_lines).min(n_lines);
+            let lines = read_lines(hit.from..to)?;
+
+            let eof_newline = (hit.from < to && to == n_lines).then_some(has_newline);
+            hunks.push((hit.from, lines, eof_newline));
+
+            // the context stops at the neighboring hunks
+            let prev_end = i
+                .checked_sub(1)
+                .map_or(0, |x| hits[x].from + hits[x].n_lines);
+            let next_from = hits.get(i + 1).map_or(n_lines, |x| x.from);
+            let before = hit
+                .from
+                .saturating_sub(show_context)
+                .max(prev_end.min(hit.from));
+            let after = (to + show_context).min(next_from.max(to));
+            context.push((read_lines(before..hit.from)?, read_lines(to..after)?));
+        }
+
+        Ok(FileHunks {
+            id,
+            line_ending,
+            hash: hash_content(&content),
+            hunks,
+            context,
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::git::{GrepHit, GrepResult};
+    use crate::hunks::LineEnding;
+    use crate::locations::{InputFormat, Location, Replacement, parse_locations};
     use crate::{Git, GrepOptions, HunkOptions, Hunks};
+    use anyhow::Result;
     use clap::Parser;
+    use std::collections::HashMap;
+
+    fn collect(
+        git: &Git,
+        pattern: &str,
+        grep_opts: &GrepOptions,
+        hunk_opts: &HunkOptions,
+    ) -> Result<Hunks> {
+        let matches = Hunks::search(git, pattern, grep_opts, hunk_opts)?;
+        Hunks::collect(git, matches, grep_opts, hunk_opts)
+    }
+
+    fn locate(
+        git: &Git,
+        locations: &[Location],
+        grep_opts: &GrepOptions,
+        hunk_opts: &HunkOptions,
+    ) -> Result<Hunks> {
+        let matches = Hunks::locate(git, locations, grep_opts, hunk_opts)?;
+        Hunks::collect(git, matches, grep_opts, hunk_opts)
+    }
 
     #[test]
     fn test_collect() {
@@ -315,107 +752,358 @@ mod tests {
         let git = Git::new().unwrap();
         let grep_opts = GrepOptions::parse_from("ge -y tests".split_whitespace());
 
-        let hunks = Hunks::collect(&git, "assert_eq", &grep_opts, opts!("ge")).unwrap();
+        let hunks = collect(&git, "assert_eq", &grep_opts, opts!("ge")).unwrap();
         assert_eq!(hunks.files.len(), 0);
         assert_eq!(hunks.hunks.len(), 0);
 
-        let hunks = Hunks::collect(&git, "assert", &grep_opts, opts!("ge")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 2);
         assert_eq!(hunks.hunks[0].2.len(), 1);
 
-        let hunks = Hunks::collect(&git, "assert", &grep_opts, opts!("ge -B2")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge -B2")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 0);
         assert_eq!(hunks.hunks[0].2.len(), 3);
 
-        let hunks = Hunks::collect(&git, "assert", &grep_opts, opts!("ge -B4")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge -B4")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 0);
         assert_eq!(hunks.hunks[0].2.len(), 3);
 
-        let hunks = Hunks::collect(&git, "assert", &grep_opts, opts!("ge -A2")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge -A2")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 2);
         assert_eq!(hunks.hunks[0].2.len(), 2);
 
-        let hunks = Hunks::collect(&git, "assert", &grep_opts, opts!("ge -C0")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge -C0")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 2);
         assert_eq!(hunks.hunks[0].2.len(), 1);
 
-        let hunks = Hunks::collect(&git, "assert", &grep_opts, opts!("ge -C1")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge -C1")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 1);
         assert_eq!(hunks.hunks[0].2.len(), 3);
 
-        let hunks = Hunks::collect(&git, "assert", &grep_opts, opts!("ge -C5")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge -C5")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 0);
         assert_eq!(hunks.hunks[0].2.len(), 4);
 
-        let hunks = Hunks::collect(&git, "fn", &grep_opts, opts!("ge --to )")).unwrap();
+        let hunks = collect(&git, "fn", &grep_opts, opts!("ge --to )")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 1);
         assert_eq!(hunks.hunks[0].2.len(), 1);
 
-        let hunks = Hunks::collect(&git, "fn", &grep_opts, opts!("ge --to }")).unwrap();
+        let hunks = collect(&git, "fn", &grep_opts, opts!("ge --to }")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 1);
         assert_eq!(hunks.hunks[0].2.len(), 3);
 
-        let hunks = Hunks::collect(&git, "fox", &grep_opts, opts!("ge")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge --to }")).unwrap();
+        assert_eq!(hunks.hunks.len(), 1);
+        assert_eq!(hunks.hunks[0].1, 2);
+        assert_eq!(hunks.hunks[0].2.len(), 1);
+
+        let hunks = collect(
+            &git,
+            "assert",
+            &grep_opts,
+            opts!("ge --to } --to-level outer"),
+        )
+        .unwrap();
+        assert_eq!(hunks.hunks.len(), 1);
+        assert_eq!(hunks.hunks[0].1, 2);
+        assert_eq!(hunks.hunks[0].2.len(), 2);
+
+        let hunks = collect(
+            &git,
+            "fn",
+            &grep_opts,
+            opts!("ge --to assert --to-level any"),
+        )
+        .unwrap();
+        assert_eq!(hunks.hunks.len(), 1);
+        assert_eq!(hunks.hunks[0].1, 1);
+        assert_eq!(hunks.hunks[0].2.len(), 2);
+
+        let hunks = collect(&git, "fox", &grep_opts, opts!("ge")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 2);
 
-        let hunks = Hunks::collect(&git, "fox", &grep_opts, opts!("ge -C5")).unwrap();
+        let hunks = collect(&git, "fox", &grep_opts, opts!("ge -C5")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
 
-        let hunks = Hunks::collect(&git, "f.\\+", &grep_opts, opts!("ge")).unwrap();
+        let hunks = collect(&git, "f.\\+", &grep_opts, opts!("ge")).unwrap();
         assert_eq!(hunks.files.len(), 2);
         assert_eq!(hunks.hunks.len(), 3);
 
-        let hunks = Hunks::collect(&git, "f.\\+", &grep_opts, opts!("ge -C5")).unwrap();
+        let hunks = collect(&git, "f.\\+", &grep_opts, opts!("ge -C5")).unwrap();
         assert_eq!(hunks.files.len(), 2);
         assert_eq!(hunks.hunks.len(), 2);
 
-        let hunks = Hunks::collect(&git, "assert", &grep_opts, opts!("ge --head 2")).unwrap();
+        let hunks = collect(&git, "dog", &grep_opts, opts!("ge")).unwrap();
+        assert_eq!(hunks.hunks.len(), 3);
+        assert_eq!(hunks.hunks[2].1, 18);
+        assert_eq!(hunks.hunks[2].2.len(), 2);
+
+        let hunks = collect(&git, "dog", &grep_opts, opts!("ge --no-merge")).unwrap();
+        assert_eq!(hunks.hunks.len(), 4);
+        assert_eq!(hunks.hunks[2].1, 18);
+        assert_eq!(hunks.hunks[2].2.len(), 1);
+        assert_eq!(hunks.hunks[3].1, 19);
+        assert_eq!(hunks.hunks[3].2.len(), 1);
+
+        let hunks = collect(&git, "dog", &grep_opts, opts!("ge --no-merge -C1")).unwrap();
+        assert_eq!(hunks.hunks.len(), 3);
+        assert_eq!(hunks.hunks[2].1, 17);
+        assert_eq!(hunks.hunks[2].2.len(), 3);
+
+        let hunks = collect(&git, "dog", &grep_opts, opts!("ge --merge-gap 3")).unwrap();
+        assert_eq!(hunks.hunks.len(), 2);
+        assert_eq!(hunks.hunks[1].1, 14);
+        assert_eq!(hunks.hunks[1].2.len(), 6);
+
+        let hunks = collect(&git, "dog", &grep_opts, opts!("ge --merge-gap 8")).unwrap();
+        assert_eq!(hunks.hunks.len(), 1);
+        assert_eq!(hunks.hunks[0].1, 5);
+        assert_eq!(hunks.hunks[0].2.len(), 15);
+
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge --head 2")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 0);
         assert_eq!(hunks.hunks[0].2.len(), 2);
 
-        let hunks = Hunks::collect(&git, "fox", &grep_opts, opts!("ge --head 3")).unwrap();
+        let hunks = collect(&git, "fox", &grep_opts, opts!("ge --head 3")).unwrap();
         assert_eq!(hunks.files.len(), 1);
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 0);
         assert_eq!(hunks.hunks[0].2.len(), 3);
 
-        let hunks = Hunks::collect(&git, "assert", &grep_opts, opts!("ge --with fn")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge --with fn")).unwrap();
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 2);
         assert_eq!(hunks.hunks[0].2.len(), 1);
 
-        let hunks = Hunks::collect(&git, "assert", &grep_opts, opts!("ge --with xyzxyz")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge --with xyzxyz")).unwrap();
         assert_eq!(hunks.hunks.len(), 0);
 
-        let hunks = Hunks::collect(&git, "assert", &grep_opts, opts!("ge --without fn")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge --without fn")).unwrap();
         assert_eq!(hunks.hunks.len(), 0);
 
-        let hunks =
-            Hunks::collect(&git, "assert", &grep_opts, opts!("ge --without xyzxyz")).unwrap();
+        let hunks = collect(&git, "assert", &grep_opts, opts!("ge --without xyzxyz")).unwrap();
         assert_eq!(hunks.hunks.len(), 1);
         assert_eq!(hunks.hunks[0].1, 2);
         assert_eq!(hunks.hunks[0].2.len(), 1);
     }
+
+    #[test]
+    fn test_collect_locations() {
+        macro_rules! opts {
+            ( $args: expr ) => {
+                &HunkOptions::parse_from($args.split_whitespace())
+            };
+        }
+
+        let git = Git::new().unwrap();
+        let grep_opts = GrepOptions::parse_from("ge".split_whitespace());
+        let locations = parse_locations(
+            "tests/test.rs:3:5: warning\ntests/quick.txt:19\ntests/quick.txt:20\n",
+            InputFormat::Auto,
+        )
+        .unwrap();
+
+        let hunks = locate(&git, &locations, &grep_opts, opts!("ge")).unwrap();
+        assert_eq!(hunks.files, vec!["tests/quick.txt", "tests/test.rs"]);
+        assert_eq!(hunks.hunks.len(), 2);
+        assert_eq!(hunks.hunks[0].1, 18);
+        assert_eq!(hunks.hunks[0].2, vec!["There is a dog.", "(dogs)."]);
+        assert_eq!(hunks.hunks[1].1, 2);
+        assert_eq!(hunks.hunks[1].2, vec!["    assert!(true);"]);
+
+        let hunks = locate(&git, &locations, &grep_opts, opts!("ge --no-merge -C1")).unwrap();
+        assert_eq!(hunks.hunks.len(), 2);
+        assert_eq!(hunks.hunks[0].1, 17);
+        assert_eq!(hunks.hunks[0].2.len(), 3);
+        assert_eq!(hunks.hunks[1].1, 1);
+        assert_eq!(hunks.hunks[1].2.len(), 3);
+
+        let hunks = locate(&git, &locations, &grep_opts, opts!("ge --to }")).unwrap();
+        assert_eq!(hunks.hunks[1].1, 2);
+        assert_eq!(hunks.hunks[1].2.len(), 1);
+
+        let grep_opts = GrepOptions::parse_from("ge -W".split_whitespace());
+        let hunks = locate(&git, &locations, &grep_opts, opts!("ge")).unwrap();
+        assert_eq!(hunks.hunks.len(), 2);
+        assert_eq!(hunks.hunks[0].1, 18);
+        assert_eq!(hunks.hunks[0].2, vec!["There is a dog.", "(dogs)."]);
+        assert_eq!(hunks.hunks[1].1, 0);
+        assert_eq!(
+            hunks.hunks[1].2,
+            vec!["#[test]", "fn test_1() {", "    assert!(true);", "}"]
+        );
+    }
+
+    #[test]
+    fn test_apply_replacements() {
+        let git = Git::new().unwrap();
+        let grep_opts = GrepOptions::parse_from("ge".split_whitespace());
+        let hunk_opts = HunkOptions::parse_from("ge".split_whitespace());
+
+        let replacement = |start, end, text: &str| Replacement {
+            path: "tests/quick.txt".to_string(),
+            start,
+            end,
+            text: text.to_string(),
+        };
+        let replacements = vec![
+            replacement((18, 11), (18, 14), "cat"),
+            replacement((18, 12), (18, 13), "overlapping"),
+            replacement((19, 1), (19, 5), "cats"),
+            replacement((3, 4), (4, 0), "fast\nbrown fox\n"),
+            replacement((9, 0), (10, 0), ""),
+        ];
+        let locations: Vec<_> = replacements.iter().map(|x| x.location()).collect();
+
+        let mut hunks = locate(&git, &locations, &grep_opts, &hunk_opts).unwrap();
+        hunks.apply_replacements(&replacements).unwrap();
+
+        assert_eq!(hunks.hunks.len(), 3);
+        assert_eq!(hunks.hunks[0].1, 3);
+        assert_eq!(
+            hunks.hunks[0].2,
+            vec!["The quick brown fox", "jumps over the lazy"]
+        );
+        assert_eq!(
+            hunks.prefill[&(0, 3)],
+            vec!["The fast", "brown fox", "jumps over the lazy"]
+        );
+        assert_eq!(hunks.prefill[&(0, 9)], vec!["again."]);
+        assert_eq!(hunks.prefill[&(0, 18)], vec!["There is a cat.", "(cats)."]);
+    }
+
+    #[test]
+    fn test_line_endings() {
+        // CRLF without the final newline
+        let path = std::env::temp_dir().join(format!("ge-crlf-{}.txt", std::process::id()));
+        std::fs::write(&path, "a\r\nb\r\nc").unwrap();
+
+        let hit = |from, n_lines| GrepHit {
+            file_id: 1,
+            from,
+            n_lines,
+            level: 0,
+        };
+        let matches = GrepResult {
+            files: vec![
+                "tests/quick.txt".to_string(),
+                path.to_str().unwrap().to_string(),
+            ],
+            hits: vec![hit(1, 5)],
+        };
+        let hunks = Hunks::collect_hunks(matches, 0);
+        std::fs::remove_file(&path).unwrap();
+
+        // the terminators are stripped, and files without hits are left LF
+        let hunks = hunks.unwrap();
+        assert_eq!(
+            hunks.hunks,
+            vec![(1, 1, vec!["b".to_string(), "c".to_string()])]
+        );
+        assert_eq!(hunks.line_endings, vec![LineEnding::Lf, LineEnding::Crlf]);
+        assert_eq!(hunks.eof_newline, HashMap::from([((1, 1), false)]));
+
+        // a stray CR is kept in LF files, and CRLF files with LF lines are refused
+        let matches = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            let matches = GrepResult {
+                files: vec![
+                    "tests/quick.txt".to_string(),
+                    path.to_str().unwrap().to_string(),
+                ],
+                hits: vec![hit(0, 3)],
+            };
+            let hunks = Hunks::collect_hunks(matches, 0);
+            std::fs::remove_file(&path).unwrap();
+            hunks
+        };
+        let hunks = matches("a\nb\r\nc\n").unwrap();
+        assert_eq!(hunks.hunks[0].2, vec!["a", "b\r", "c"]);
+        assert_eq!(hunks.line_endings[1], LineEnding::Lf);
+        assert!(matches("a\r\nb\nc\r\n").is_err());
+    }
+
+    #[test]
+    fn test_collect_order() {
+        let git = Git::new().unwrap();
+        let grep_opts = GrepOptions::parse_from("ge".split_whitespace());
+        let hunk_opts = HunkOptions::parse_from("ge".split_whitespace());
+
+        // files are read in parallel, but the hunks are in the order of the hits
+        let matches = Hunks::search(&git, "e", &grep_opts, &hunk_opts).unwrap();
+        let expected: Vec<_> = matches.hits.iter().map(|x| (x.file_id, x.from)).collect();
+        let hunks = Hunks::collect(&git, matches, &grep_opts, &hunk_opts).unwrap();
+        let actual: Vec<_> = hunks.hunks.iter().map(|x| (x.0, x.1)).collect();
+        assert!(hunks.files.len() > 1);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_show_context() {
+        let git = Git::new().unwrap();
+        let grep_opts = GrepOptions::parse_from("ge -y tests/quick.txt".split_whitespace());
+
+        let hunk_opts = HunkOptions::parse_from("ge --show-context 2".split_whitespace());
+        let hunks = collect(&git, "fox", &grep_opts, &hunk_opts).unwrap();
+        assert_eq!(hunks.hunks.len(), 2);
+        assert_eq!(
+            hunks.context[&(0, 3)],
+            (
+                vec!["# Title here".to_string(), "".to_string()],
+                vec!["jumps over the lazy".to_string(), "dog.".to_string()]
+            )
+        );
+
+        // the context stops at the neighboring hunks
+        let hunk_opts =
+            HunkOptions::parse_from("ge --show-context 2 -C1 --no-merge".split_whitespace());
+        let hunks = collect(&git, "dog", &grep_opts, &hunk_opts).unwrap();
+        assert_eq!(hunks.context[&(0, 17)].0, vec!["## Subsection 3"]);
+        assert_eq!(hunks.context[&(0, 13)].1, vec!["## Subsection 3"]);
+    }
+
+    #[test]
+    fn test_lexical_class() {
+        // assume tests/fixtures/classes.rs exists
+        let git = Git::new().unwrap();
+        let grep_opts =
+            GrepOptions::parse_from("ge -y tests/fixtures/classes.rs".split_whitespace());
+
+        let search = |args: &str| {
+            let hunk_opts = HunkOptions::parse_from(args.split_whitespace());
+            let matches = Hunks::search(&git, "owl", &grep_opts, &hunk_opts).unwrap();
+            matches.hits.iter().map(|x| x.from).collect::<Vec<_>>()
+        };
+        assert_eq!(search("ge --no-merge"), vec![0, 1, 2, 3, 5]);
+        assert_eq!(search("ge --no-merge --code-only"), vec![1, 2]);
+        assert_eq!(search("ge --no-merge --comments-only"), vec![0, 5]);
+        assert_eq!(search("ge --no-merge --strings-only"), vec![3]);
+
+        assert!(
+            HunkOptions::try_parse_from("ge --code-only --strings-only".split_whitespace())
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,433 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Class {
+    Code,
+    Comment,
+    String,
+}
+
+// delimiters of a language; strings may have backslash escapes
+struct Syntax {
+    line_comments: &'static [&'static str],
+    block_comments: &'static [(&'static str, &'static str)],
+    strings: &'static [(&'static str, &'static str, bool)],
+    nested_comments: bool, // block comments nest
+    raw_strings: bool,     // r"..." and r#"..."# without escapes
+    char_literals: bool,   // a single quote opens a string only around a char
+}
+
+const C_LIKE: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    strings: &[("\"", "\"", true), ("'", "'", true), ("`", "`", false)],
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+// single quotes are also lifetimes in Rust, so they open strings only as chars
+const RUST: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    strings: &[("\"", "\"", true)],
+    nested_comments: true,
+    raw_strings: true,
+    char_literals: true,
+};
+
+// "//" is not a comment in CSS, as in url(http://...)
+const CSS: Syntax = Syntax {
+    line_comments: &[],
+    block_comments: &[("/*", "*/")],
+    strings: &[("\"", "\"", true), ("'", "'", true)],
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+const PYTHON: Syntax = Syntax {
+    line_comments: &["#"],
+    block_comments: &[],
+    strings: &[
+        ("\"\"\"", "\"\"\"", true),
+        ("'''", "'''", true),
+        ("\"", "\"", true),
+        ("'", "'", true),
+    ],
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+const HASH: Syntax = Syntax {
+    line_comments: &["#"],
+    block_comments: &[],
+    strings: &[("\"", "\"", true), ("'", "'", false)],
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+const DASH: Syntax = Syntax {
+    line_comments: &["--"],
+    block_comments: &[("/*", "*/")],
+    strings: &[("'", "'", false), ("\"", "\"", false)],
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+const MARKUP: Syntax = Syntax {
+    line_comments: &[],
+    block_comments: &[("<!--", "-->")],
+    strings: &[],
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+fn syntax(path: &str) -> Option<&'static Syntax> {
+    let ext = path.rsplit_once('.')?.1;
+    match ext {
+        "rs" => Some(&RUST),
+        "c" | "h" | "cc" | "cpp" | "cxx" | "hpp" | "hh" | "m" | "mm" | "java" | "kt" | "kts"
+        | "scala" | "cs" | "go" | "swift" | "dart" | "js" | "jsx" | "mjs" | "cjs" | "ts"
+        | "tsx" | "scss" | "proto" | "zig" => Some(&C_LIKE),
+        "css" => Some(&CSS),
+        "py" | "pyi" => Some(&PYTHON),
+        "sh" | "bash" | "zsh" | "rb" | "pl" | "pm" | "r" | "toml" | "yaml" | "yml" | "cmake"
+        | "mk" | "nix" => Some(&HASH),
+        "sql" | "lua" | "hs" | "elm" => Some(&DASH),
+        "html" | "htm" | "xml" | "svg" | "md" | "vue" => Some(&MARKUP),
+        _ => None,
+    }
+}
+
+// the token we are in; raw strings close with as many '#' as they open with, and nested comments
+// with as many closing delimiters as they have opened
+#[derive(Copy, Clone)]
+struct Token {
+    class: Class,
+    open: &'static str,
+    close: &'static str,
+    escape: bool,
+    hashes: usize,
+    depth: usize,
+}
+
+impl Token {
+    fn new(class: Class, open: &'static str, close: &'static str, escape: bool) -> Self {
+        Token {
+            class,
+            open,
+            close,
+            escape,
+            hashes: 0,
+            depth: 0,
+        }
+    }
+}
+
+// the length of r"..." or r#"..."# openings at the head of `rest`, and the number of '#'
+fn raw_string(content: &[u8], i: usize) -> Option<(usize, usize)> {
+    let is_ident = |x: u8| x.is_ascii_alphanumeric() || x == b'_';
+    let rest = &content[i..];
+
+    // "br" opens byte strings, but "xr" is the end of an identifier
+    let prefix = match i.checked_sub(1).map(|x| content[x]) {
+        Some(b'b') if i < 2 || !is_ident(content[i - 2]) => true,
+        Some(x) => !is_ident(x),
+        None => true,
+    };
+    if !prefix || rest.first() != Some(&b'r') {
+        return None;
+    }
+    let hashes = rest[1..].iter().take_while(|&&x| x == b'#').count();
+    (rest.get(1 + hashes) == Some(&b'"')).then_some((2 + hashes, hashes))
+}
+
+// the length of a char literal without escapes at the head of `rest`, or None for lifetimes and
+// chars with escapes
+fn char_literal(rest: &[u8]) -> Option<usize> {
+    let len = match rest.get(1)? {
+        b'\\' | b'\'' => return None,
+        x if *x < 0x80 => 1,
+        x if *x >= 0xf0 => 4,
+        x if *x >= 0xe0 => 3,
+        _ => 2,
+    };
+    (rest.get(1 + len) == Some(&b'\'')).then_some(2 + len)
+}
+
+// classifies the byte offsets in the content, which must be sorted in ascending order
+fn classify(content: &[u8], syntax: &Syntax, offsets: &[usize]) -> Vec<Class> {
+    let mut classes = vec![Class::Code; offsets.len()];
+
+    let mut state: Option<Token> = None;
+    let mut k = 0;
+    let mut i = 0;
+    while i < content.len() && k < offsets.len() {
+        let rest = &content[i..];
+        let (class, len, next) = match state {
+            None => {
+                let opens = syntax
+                    .line_comments
+                    .iter()
+                    .map(|x| Token::new(Class::Comment, x, "\n", false))
+                    .chain(
+                        syntax
+                            .block_comments
+                            .iter()
+                            .map(|x| Token::new(Class::Comment, x.0, x.1, false)),
+                    )
+                    .chain(
+                        syntax
+                            .strings
+                            .iter()
+                            .map(|x| Token::new(Class::String, x.0, x.1, x.2)),
+                    );
+
+                // the first listed delimiter wins, so longer ones come first
+                let mut opens = opens.filter(|x| rest.starts_with(x.open.as_bytes()));
+                if let Some((len, hashes)) =
+                    syntax.raw_strings.then(|| raw_string(content, i)).flatten()
+                {
+                    let token = Token {
+                        hashes,
+                        ..Token::new(Class::String, "", "\"", false)
+                    };
+                    (Class::String, len, Some(token))
+                } else if syntax.char_literals && rest[0] == b'\'' {
+                    // lifetimes are left as code
+                    match (rest.get(1), char_literal(rest)) {
+                        (_, Some(len)) => (Class::String, len, None),
+                        (Some(b'\\'), None) => (
+                            Class::String,
+                            1,
+                            Some(Token::new(Class::String, "'", "'", true)),
+                        ),
+                        _ => (Class::Code, 1, None),
+                    }
+                } else if let Some(token) = opens.next() {
+                    (token.class, token.open.len(), Some(token))
+                } else {
+                    (Class::Code, 1, None)
+                }
+            }
+            Some(mut token) => {
+                let closes = rest.starts_with(token.close.as_bytes())
+                    && rest[token.close.len()..]
+                        .iter()
+                        .take(token.hashes)
+                        .filter(|&&x| x == b'#')
+                        .count()
+                        == token.hashes;
+                if token.escape && rest[0] == b'\\' {
+                    (token.class, 2, state)
+                } else if closes {
+                    let len = token.close.len() + token.hashes;
+                    if token.depth > 0 {
+                        token.depth -= 1;
+                        (token.class, len, Some(token))
+                    } else {
+                        (token.class, len, None)
+                    }
+                } else if syntax.nested_comments
+                    && token.class == Class::Comment
+                    && token.close != "\n"
+                    && rest.starts_with(token.open.as_bytes())
+                {
+                    token.depth += 1;
+                    (token.class, token.open.len(), Some(token))
+                } else {
+                    (token.class, 1, state)
+                }
+            }
+        };
+
+        while k < offsets.len() && offsets[k] < i + len {
+            classes[k] = class;
+            k += 1;
+        }
+        i += len;
+        state = next;
+    }
+
+    // offsets at the end of unterminated tokens
+    if let Some(token) = state {
+        classes[k..].fill(token.class);
+    }
+    classes
+}
+
+// classifies matches given as (path, zero-origin line, zero-origin byte column). files in unknown
+// languages are all code
+pub fn classify_matches(matches: &[(String, usize, usize)]) -> Result<Vec<Class>> {
+    let mut by_file: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, (path, _, _)) in matches.iter().enumerate() {
+        by_file.entry(path.as_str()).or_default().push(i);
+    }
+
+    let mut classes = vec![Class::Code; matches.len()];
+    for (path, indices) in by_file {
+        let Some(syntax) = syntax(path) else {
+            continue;
+        };
+        let content =
+            std::fs::read(path).with_context(|| format!("failed to read {path:?}. aborting."))?;
+
+        let mut heads = vec![0];
+        heads.extend(
+            content
+                .iter()
+                .enumerate()
+                .filter(|x| *x.1 == b'\n')
+                .map(|x| x.0 + 1),
+        );
+
+        let mut offsets: Vec<_> = indices
+            .iter()
+            .map(|&i| {
+                let (_, line, col) = matches[i];
+                let head = heads.get(line).copied().unwrap_or(content.len());
+                ((head + col).min(content.len()), i)
+            })
+            .collect();
+        offsets.sort();
+
+        let sorted: Vec<_> = offsets.iter().map(|x| x.0).collect();
+        for ((_, i), class) in offsets.iter().zip(classify(&content, syntax, &sorted)) {
+            classes[*i] = class;
+        }
+    }
+    Ok(classes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::{C_LIKE, CSS, Class, PYTHON, RUST, classify, syntax};
+
+    #[test]
+    fn test_classify() {
+        let offset = |content: &str, needle: &str| content.find(needle).unwrap();
+
+        let content = "let s = \"A \\\" B\"; // C\n/* D\n E */ F('G', 'H');\n";
+        let needles = ["let", "A", "B", "C", "D", "E", "F", "G"];
+        let offsets: Vec<_> = needles.iter().map(|x| offset(content, x)).collect();
+        assert_eq!(
+            classify(content.as_bytes(), &C_LIKE, &offsets),
+            vec![
+                Class::Code,
+                Class::String,
+                Class::String,
+                Class::Comment,
+                Class::Comment,
+                Class::Comment,
+                Class::Code,
+                Class::String,
+            ]
+        );
+
+        // lifetimes don't open strings in Rust
+        let content = "fn f<'a>(x: &'a str) -> &'a str { \"x\" }";
+        let offsets = [offset(content, "str"), offset(content, "\"x") + 1];
+        assert_eq!(
+            classify(content.as_bytes(), &RUST, &offsets),
+            vec![Class::Code, Class::String]
+        );
+
+        // chars are strings, even quotes and escapes, while lifetimes are code
+        let content = "fn f<'a>(x: &'a u8) { g('\\'', 'q', '\\n', 'é', \"//\"); h(x) }";
+        let offsets = [
+            offset(content, "'a>"),
+            offset(content, "'a u8"),
+            offset(content, "'\\''") + 2,
+            offset(content, "q"),
+            offset(content, "n'"),
+            offset(content, "é"),
+            offset(content, "//"),
+            offset(content, "h(x)"),
+        ];
+        assert_eq!(
+            classify(content.as_bytes(), &RUST, &offsets),
+            vec![
+                Class::Code,
+                Class::Code,
+                Class::String,
+                Class::String,
+                Class::String,
+                Class::String,
+                Class::String,
+                Class::Code,
+            ]
+        );
+
+        // raw strings have no escapes, and close with as many '#' as they open with
+        let content = "r\"A\\\" B; br#\"C\"D\"# /* E */ r##\"F\"#G\"## var\"H\"";
+        let needles = ["A", "B", "C", "D", "E", "F", "G", "var", "H"];
+        let offsets: Vec<_> = needles.iter().map(|x| offset(content, x)).collect();
+        assert_eq!(
+            classify(content.as_bytes(), &RUST, &offsets),
+            vec![
+                Class::String,
+                Class::Code,
+                Class::String,
+                Class::String,
+                Class::Comment,
+                Class::String,
+                Class::String,
+                Class::Code,
+                Class::String,
+            ]
+        );
+
+        // block comments nest in Rust but not in C, and comment markers in strings are strings
+        let content = "/* A /* B */ C */ D \"/* E\" F // G \"H\"";
+        let needles = ["A", "B", "C", "D", "E", "F", "G", "H"];
+        let offsets: Vec<_> = needles.iter().map(|x| offset(content, x)).collect();
+        assert_eq!(
+            classify(content.as_bytes(), &RUST, &offsets),
+            vec![
+                Class::Comment,
+                Class::Comment,
+                Class::Comment,
+                Class::Code,
+                Class::String,
+                Class::Code,
+                Class::Comment,
+                Class::Comment,
+            ]
+        );
+        assert_eq!(
+            classify(content.as_bytes(), &C_LIKE, &offsets[2..4]),
+            vec![Class::Code, Class::Code]
+        );
+
+        // CSS has block comments only
+        let content = "a { background: url(http://x/y.png); } /* A */ b { content: '//' }";
+        let offsets = [
+            offset(content, "x/y"),
+            offset(content, "A"),
+            offset(content, "//'"),
+        ];
+        assert_eq!(
+            classify(content.as_bytes(), &CSS, &offsets),
+            vec![Class::Code, Class::Comment, Class::String]
+        );
+        assert_eq!(syntax("style.css").map(|x| x.line_comments.len()), Some(0));
+
+        // triple quotes span lines, and unterminated tokens last until the end
+        let content = "x = \"\"\"doc\nstring\"\"\" # comment\n'open";
+        let offsets = [
+            offset(content, "string"),
+            offset(content, "comment"),
+            content.len() - 1,
+        ];
+        assert_eq!(
+            classify(content.as_bytes(), &PYTHON, &offsets),
+            vec![Class::String, Class::Comment, Class::String]
+        );
+    }
+}
@@ -0,0 +1,604 @@
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use serde_json::Value;
+use std::io::Read;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InputFormat {
+    Auto,
+    List,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+    pub path: String,
+    pub line: usize, // zero-origin
+    pub n_lines: usize,
+}
+
+impl Location {
+    fn new(path: &str, line: usize, n_lines: usize) -> Option<Self> {
+        // the editors and compilers we know report one-origin line numbers
+        if line == 0 {
+            return None;
+        }
+
+        Some(Location {
+            path: normalize_path(path)?,
+            line: line - 1,
+            n_lines: n_lines.max(1),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Replacement {
+    pub path: String,
+    pub start: (usize, usize), // zero-origin (line, column in characters)
+    pub end: (usize, usize),   // exclusive
+    pub text: String,
+}
+
+impl Replacement {
+    fn new(path: &str, start: (usize, usize), end: (usize, usize), text: &str) -> Option<Self> {
+        // one-origin lines and columns as well
+        if start.0 == 0 || start.1 == 0 || end < start {
+            return None;
+        }
+
+        Some(Replacement {
+            path: normalize_path(path)?,
+            start: (start.0 - 1, start.1 - 1),
+            end: (end.0 - 1, end.1.saturating_sub(1)),
+            text: text.to_string(),
+        })
+    }
+
+    pub fn location(&self) -> Location {
+        Location {
+            path: self.path.clone(),
+            line: self.start.0,
+            n_lines: self.end.0 + 1 - self.start.0,
+        }
+    }
+}
+
+fn normalize_path(path: &str) -> Option<String> {
+    let path = path.trim();
+    let path = path.strip_prefix("file://").unwrap_or(path);
+    let path = path.strip_prefix("./").unwrap_or(path);
+    if path.is_empty() {
+        return None;
+    }
+
+    // paths are relative to the current directory as in the output of git-grep
+    if let Ok(cwd) = std::env::current_dir()
+        && let Ok(stripped) = std::path::Path::new(path).strip_prefix(cwd)
+        && let Some(stripped) = stripped.to_str()
+    {
+        return Some(stripped.to_string());
+    }
+    Some(path.to_string())
+}
+
+pub fn read_input(name: &str) -> Result<String> {
+    let mut buf = String::new();
+    if name == "-" {
+        std::io::stdin()
+            .read_to_string(&mut buf)
+            .context("failed to read locations from stdin. aborting.")?;
+    } else {
+        buf = std::fs::read_to_string(name)
+            .with_context(|| format!("failed to read locations from {name:?}. aborting."))?;
+    }
+    Ok(buf)
+}
+
+pub fn parse_locations(input: &str, format: InputFormat) -> Result<Vec<Location>> {
+    let format = match format {
+        InputFormat::Auto => detect_format(input),
+        _ => format,
+    };
+
+    let mut locations = match format {
+        InputFormat::List => parse_list(input),
+        InputFormat::Json => parse_json(input)?,
+        InputFormat::Auto => unreachable!(),
+    };
+
+    locations.sort();
+    locations.dedup();
+    Ok(locations)
+}
+
+fn detect_format(input: &str) -> InputFormat {
+    let first = input.lines().map(|x| x.trim()).find(|x| !x.is_empty());
+    match first {
+        Some(x) if x.starts_with('{') || x.starts_with('[') => InputFormat::Json,
+        _ => InputFormat::List,
+    }
+}
+
+fn parse_list(input: &str) -> Vec<Location> {
+    input.lines().filter_map(parse_list_line).collect()
+}
+
+fn parse_list_line(line: &str) -> Option<Location> {
+    // accepts "path:line[:col][: message]" (grep, compilers), "path|line col N| message" (quickfix
+    // window), and "path(line[,col]): message" (msvc). paths may contain the delimiters, so we
+    // take the first delimiter followed by a line number.
+    for (pos, delim) in line.char_indices() {
+        let terminators: &[char] = match delim {
+            ':' => &[':', ' '],
+            '|' => &['|', ' '],
+            '(' => &[')', ','],
+            _ => continue,
+        };
+
+        let rem = &line[pos + 1..];
+        let len = rem.bytes().take_while(|x| x.is_ascii_digit()).count();
+        let Ok(at) = rem[..len].parse::<usize>() else {
+            continue;
+        };
+
+        match rem[len..].chars().next() {
+            None if delim == ':' => {}
+            Some(x) if terminators.contains(&x) => {}
+            _ => continue,
+        }
+        return Location::new(&line[..pos], at, 1);
+    }
+    None
+}
+
+fn parse_json(input: &str) -> Result<Vec<Location>> {
+    let mut locations = Vec::new();
+    for value in json_values(input)? {
+        collect_json(&value, &mut locations)?;
+    }
+    Ok(locations)
+}
+
+fn json_values(input: &str) -> Result<Vec<Value>> {
+    // ast-grep and SARIF emit a single (possibly pretty-printed) document, and cargo, rustc,
+    // and ripgrep emit one document per line
+    if let Ok(value) = serde_json::from_str(input) {
+        return Ok(vec![value]);
+    }
+    json_lines(input).collect()
+}
+
+fn json_lines(input: &str) -> impl Iterator<Item = Result<Value>> {
+    input.lines().enumerate().filter_map(|(i, line)| {
+        let line = line.trim();
+        if !line.starts_with('{') && !line.starts_with('[') {
+            // mixed with human-readable messages
+            return None;
+        }
+
+        let value = serde_json::from_str(line)
+            .with_context(|| format!("failed to parse line {} as JSON. aborting.", i + 1));
+        Some(value)
+    })
+}
+
+fn collect_json(value: &Value, locations: &mut Vec<Location>) -> Result<()> {
+    if let Some(array) = value.as_array() {
+        for value in array {
+            collect_json(value, locations)?;
+        }
+        return Ok(());
+    }
+
+    // cargo wraps rustc diagnostics in "compiler-message"s and emits other messages as well
+    if let Some(reason) = value.get("reason").and_then(|x| x.as_str()) {
+        if reason == "compiler-message"
+            && let Some(message) = value.get("message")
+        {
+            collect_rustc_spans(message, locations);
+        }
+        return Ok(());
+    }
+
+    if value.get("spans").is_some() {
+        collect_rustc_spans(value, locations);
+        return Ok(());
+    }
+
+    // ripgrep emits "begin", "match", "context", "end", and "summary" messages
+    if let Some(kind) = value.get("type").and_then(|x| x.as_str()) {
+        if kind == "match"
+            && let Some(data) = value.get("data")
+        {
+            collect_rg_match(data, locations);
+        }
+        return Ok(());
+    }
+
+    if value.get("file").is_some() && value.get("range").is_some() {
+        collect_ast_grep_match(value, locations);
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "unknown JSON message {}. aborting.",
+        truncate(&value.to_string(), 80)
+    ))
+}
+
+fn collect_rustc_spans(diagnostic: &Value, locations: &mut Vec<Location>) {
+    let Some(spans) = diagnostic.get("spans").and_then(|x| x.as_array()) else {
+        return;
+    };
+
+    for span in spans {
+        if span.get("is_primary").and_then(|x| x.as_bool()) != Some(true) {
+            continue;
+        }
+
+        let field = |name: &str| span.get(name).and_then(|x| x.as_u64()).map(|x| x as usize);
+        let (Some(path), Some(start), Some(end)) = (
+            span.get("file_name").and_then(|x| x.as_str()),
+            field("line_start"),
+            field("line_end"),
+        ) else {
+            continue;
+        };
+
+        if is_external(path) {
+            continue;
+        }
+
+        if let Some(location) = Location::new(path, start, end + 1 - start.min(end)) {
+            locations.push(location);
+        }
+    }
+}
+
+fn collect_rg_match(data: &Value, locations: &mut Vec<Location>) {
+    // non-UTF-8 paths come in "bytes" instead of "text"; we can't handle them anyway
+    let path = data
+        .get("path")
+        .and_then(|x| x.get("text"))
+        .and_then(|x| x.as_str());
+    let line = data.get("line_number").and_then(|x| x.as_u64());
+    let (Some(path), Some(line)) = (path, line) else {
+        return;
+    };
+
+    // "lines" spans multiple lines for multiline matches
+    let n_lines = data
+        .get("lines")
+        .and_then(|x| x.get("text"))
+        .and_then(|x| x.as_str())
+        .map_or(1, |x| x.trim_end_matches('\n').lines().count());
+
+    if let Some(location) = Location::new(path, line as usize, n_lines) {
+        locations.push(location);
+    }
+}
+
+fn collect_ast_grep_match(value: &Value, locations: &mut Vec<Location>) {
+    // lines are zero-origin in ast-grep
+    let line = |name: &str| {
+        value
+            .get("range")
+            .and_then(|x| x.get(name))
+            .and_then(|x| x.get("line"))
+            .and_then(|x| x.as_u64())
+            .map(|x| x as usize)
+    };
+    let path = value.get("file").and_then(|x| x.as_str());
+    let (Some(path), Some(start), Some(end)) = (path, line("start"), line("end")) else {
+        return;
+    };
+
+    if let Some(location) = Location::new(path, start + 1, end + 1 - start.min(end)) {
+        locations.push(location);
+    }
+}
+
+fn is_external(path: &str) -> bool {
+    // spans in the toolchain or in external crates
+    let path = std::path::Path::new(path);
+    path.is_absolute() && !std::env::current_dir().is_ok_and(|x| path.starts_with(x))
+}
+
+pub fn parse_suggestions(input: &str) -> Result<Vec<Replacement>> {
+    let mut replacements = Vec::new();
+
+    for value in json_values(input)? {
+        if value.get("runs").is_some() {
+            collect_sarif_fixes(&value, &mut replacements);
+            continue;
+        }
+
+        match value.get("reason").and_then(|x| x.as_str()) {
+            Some("compiler-message") => {
+                if let Some(message) = value.get("message") {
+                    collect_rustc_suggestions(message, &mut replacements);
+                }
+            }
+            Some(_) => {}
+            None => collect_rustc_suggestions(&value, &mut replacements),
+        }
+    }
+
+    replacements.sort();
+    replacements.dedup();
+    Ok(replacements)
+}
+
+fn collect_rustc_suggestions(diagnostic: &Value, replacements: &mut Vec<Replacement>) {
+    let spans = diagnostic.get("spans").and_then(|x| x.as_array());
+    for span in spans.into_iter().flatten() {
+        let applicability = span
+            .get("suggestion_applicability")
+            .and_then(|x| x.as_str());
+        let text = span.get("suggested_replacement").and_then(|x| x.as_str());
+        let (Some("MachineApplicable"), Some(text)) = (applicability, text) else {
+            continue;
+        };
+
+        let field = |name: &str| span.get(name).and_then(|x| x.as_u64()).map(|x| x as usize);
+        let (Some(path), Some(line_start), Some(col_start), Some(line_end), Some(col_end)) = (
+            span.get("file_name").and_then(|x| x.as_str()),
+            field("line_start"),
+            field("column_start"),
+            field("line_end"),
+            field("column_end"),
+        ) else {
+            continue;
+        };
+
+        if is_external(path) {
+            continue;
+        }
+
+        if let Some(replacement) =
+            Replacement::new(path, (line_start, col_start), (line_end, col_end), text)
+        {
+            replacements.push(replacement);
+        }
+    }
+
+    // suggestions are usually attached to the "help" children
+    let children = diagnostic.get("children").and_then(|x| x.as_array());
+    for child in children.into_iter().flatten() {
+        collect_rustc_suggestions(child, replacements);
+    }
+}
+
+fn collect_sarif_fixes(log: &Value, replacements: &mut Vec<Replacement>) {
+    let array = |value: &Value, name: &str| -> Vec<Value> {
+        value
+            .get(name)
+            .and_then(|x| x.as_array())
+            .cloned()
+            .unwrap_or_default()
+    };
+    let field = |value: &Value, name: &str| value.get(name).and_then(|x| x.as_u64());
+
+    for run in array(log, "runs") {
+        for result in array(&run, "results") {
+            for fix in array(&result, "fixes") {
+                for change in array(&fix, "artifactChanges") {
+                    let path = change
+                        .get("artifactLocation")
+                        .and_then(|x| x.get("uri"))
+                        .and_then(|x| x.as_str());
+                    let Some(path) = path else {
+                        continue;
+                    };
+
+                    for replacement in array(&change, "replacements") {
+                        let Some(region) = replacement.get("deletedRegion") else {
+                            continue;
+                        };
+                        let Some(start_line) = field(region, "startLine") else {
+                            continue;
+                        };
+
+                        // omitted columns extend the region to the ends of the lines
+                        let start_col = field(region, "startColumn").unwrap_or(1);
+                        let end_line = field(region, "endLine").unwrap_or(start_line);
+                        let end_col = field(region, "endColumn").unwrap_or(u32::MAX as u64);
+                        let text = replacement
+                            .get("insertedContent")
+                            .and_then(|x| x.get("text"))
+                            .and_then(|x| x.as_str())
+                            .unwrap_or("");
+
+                        let start = (start_line as usize, start_col as usize);
+                        let end = (end_line as usize, end_col as usize);
+                        if let Some(replacement) = Replacement::new(path, start, end, text) {
+                            replacements.push(replacement);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn truncate(s: &str, len: usize) -> String {
+    if s.chars().count() <= len {
+        return s.to_string();
+    }
+    let mut s: String = s.chars().take(len).collect();
+    s.push_str("...");
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::locations::{
+        InputFormat, Location, Replacement, parse_locations, parse_suggestions,
+    };
+
+    fn loc(path: &str, line: usize, n_lines: usize) -> Location {
+        Location {
+            path: path.to_string(),
+            line,
+            n_lines,
+        }
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let input = concat!(
+            "src/main.rs:10\n",
+            "./src/git.rs:5:12: warning: unused variable\n",
+            "src/hunks.rs|3 col 4| error: mismatched types\n",
+            "src/patch.rs(7,2): error C2065\n",
+            "a:b.txt:2:1:message\n",
+            "not a location\n",
+            "src/main.rs:0\n",
+            "src/main.rs:10:3\n",
+            "src/pager.rs:4: call foo(1)|2 x\n",
+        );
+        let locations = parse_locations(input, InputFormat::Auto).unwrap();
+        assert_eq!(
+            locations,
+            vec![
+                loc("a:b.txt", 1, 1),
+                loc("src/git.rs", 4, 1),
+                loc("src/hunks.rs", 2, 1),
+                loc("src/main.rs", 9, 1),
+                loc("src/pager.rs", 3, 1),
+                loc("src/patch.rs", 6, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let input = concat!(
+            r#"{"reason":"compiler-artifact","target":{}}"#,
+            "\n",
+            r#"{"reason":"compiler-message","message":{"message":"unused","spans":["#,
+            r#"{"file_name":"src/main.rs","line_start":3,"line_end":4,"is_primary":true},"#,
+            r#"{"file_name":"src/git.rs","line_start":1,"line_end":1,"is_primary":false}]}}"#,
+            "\n",
+            "   Compiling ge v0.0.4\n",
+            r#"{"$message_type":"diagnostic","message":"m","spans":["#,
+            r#"{"file_name":"/rustc/library/core/src/lib.rs","line_start":1,"line_end":1,"is_primary":true},"#,
+            r#"{"file_name":"src/patch.rs","line_start":8,"line_end":8,"is_primary":true}]}"#,
+            "\n",
+            r#"{"reason":"build-finished","success":false}"#,
+            "\n",
+        );
+        let locations = parse_locations(input, InputFormat::Auto).unwrap();
+        assert_eq!(
+            locations,
+            vec![loc("src/main.rs", 2, 2), loc("src/patch.rs", 7, 1)]
+        );
+
+        assert!(parse_locations("{\"foo\":1}\n", InputFormat::Json).is_err());
+        assert!(parse_locations("{broken\n", InputFormat::Json).is_err());
+        assert!(
+            parse_locations("src/main.rs:1\n", InputFormat::Json)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_parse_rg_and_ast_grep() {
+        let input = concat!(
+            r#"{"type":"begin","data":{"path":{"text":"src/main.rs"}}}"#,
+            "\n",
+            r#"{"type":"match","data":{"path":{"text":"src/main.rs"},"lines":{"text":"foo\n"},"#,
+            r#""line_number":3,"absolute_offset":10,"submatches":[]}}"#,
+            "\n",
+            r#"{"type":"context","data":{"path":{"text":"src/main.rs"},"lines":{"text":"bar\n"},"#,
+            r#""line_number":4,"absolute_offset":14,"submatches":[]}}"#,
+            "\n",
+            r#"{"type":"match","data":{"path":{"text":"./src/git.rs"},"lines":{"text":"a\nb\n"},"#,
+            r#""line_number":7,"absolute_offset":30,"submatches":[]}}"#,
+            "\n",
+            r#"{"type":"end","data":{"path":{"text":"src/main.rs"}}}"#,
+            "\n",
+            r#"{"type":"summary","data":{}}"#,
+            "\n",
+        );
+        let locations = parse_locations(input, InputFormat::Auto).unwrap();
+        assert_eq!(
+            locations,
+            vec![loc("src/git.rs", 6, 2), loc("src/main.rs", 2, 1)]
+        );
+
+        // pretty-printed array (--json) and one match per line (--json=stream)
+        let input = r#"[
+          {
+            "text": "foo()",
+            "range": {
+              "byteOffset": {"start": 10, "end": 15},
+              "start": {"line": 4, "column": 2},
+              "end": {"line": 6, "column": 1}
+            },
+            "file": "src/hunks.rs",
+            "language": "Rust"
+          }
+        ]"#;
+        let locations = parse_locations(input, InputFormat::Auto).unwrap();
+        assert_eq!(locations, vec![loc("src/hunks.rs", 4, 3)]);
+
+        let input = concat!(
+            r#"{"text":"a","range":{"start":{"line":0,"column":0},"end":{"line":0,"column":1}},"file":"a.rs"}"#,
+            "\n",
+            r#"{"text":"b","range":{"start":{"line":2,"column":0},"end":{"line":2,"column":1}},"file":"a.rs"}"#,
+            "\n",
+        );
+        let locations = parse_locations(input, InputFormat::Json).unwrap();
+        assert_eq!(locations, vec![loc("a.rs", 0, 1), loc("a.rs", 2, 1)]);
+    }
+
+    #[test]
+    fn test_parse_suggestions() {
+        let input = concat!(
+            r#"{"reason":"compiler-message","message":{"message":"m","spans":[],"children":["#,
+            r#"{"message":"help","spans":[{"file_name":"src/main.rs","line_start":3,"#,
+            r#""line_end":3,"column_start":5,"column_end":9,"is_primary":true,"#,
+            r#""suggested_replacement":"bar","suggestion_applicability":"MachineApplicable"}],"#,
+            r#""children":[]},"#,
+            r#"{"message":"help","spans":[{"file_name":"src/main.rs","line_start":5,"#,
+            r#""line_end":5,"column_start":1,"column_end":2,"is_primary":true,"#,
+            r#""suggested_replacement":"baz","suggestion_applicability":"MaybeIncorrect"}],"#,
+            r#""children":[]}]}}"#,
+            "\n",
+        );
+        let replacements = parse_suggestions(input).unwrap();
+        assert_eq!(
+            replacements,
+            vec![Replacement {
+                path: "src/main.rs".to_string(),
+                start: (2, 4),
+                end: (2, 8),
+                text: "bar".to_string(),
+            }]
+        );
+
+        let input = r#"{
+          "version": "2.1.0",
+          "runs": [{"results": [{"fixes": [{"artifactChanges": [{
+            "artifactLocation": {"uri": "src/git.rs"},
+            "replacements": [
+              {"deletedRegion": {"startLine": 2, "startColumn": 3, "endLine": 4, "endColumn": 1},
+               "insertedContent": {"text": "x\ny"}},
+              {"deletedRegion": {"startLine": 7}}
+            ]
+          }]}]}]}]
+        }"#;
+        let replacements = parse_suggestions(input).unwrap();
+        assert_eq!(replacements.len(), 2);
+        assert_eq!(replacements[0].start, (1, 2));
+        assert_eq!(replacements[0].end, (3, 0));
+        assert_eq!(replacements[0].text, "x\ny");
+        assert_eq!(replacements[0].location().n_lines, 3);
+        assert_eq!(replacements[1].start, (6, 0));
+        assert_eq!(replacements[1].end.0, 6);
+        assert_eq!(replacements[1].text, "");
+    }
+}
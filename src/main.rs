@@ -1,24 +1,59 @@
 mod editor;
 mod git;
 mod hunks;
+mod lexer;
+mod locations;
 mod pager;
 mod patch;
+mod select;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
 use clap::Parser;
 use std::io::{BufWriter, Write};
 
-use crate::editor::Editor;
+use crate::editor::{Editor, prompt};
 use crate::git::{Git, GrepOptions};
-use crate::hunks::{HunkOptions, Hunks};
+use crate::hunks::{Annotation, HunkOptions, Hunks};
+use crate::locations::InputFormat;
 use crate::pager::Pager;
-use crate::patch::{HalfDiffConfig, PatchBuilder};
+use crate::patch::{
+    Changed, Conflicts, Diagnostics, HalfDiffConfig, PatchBuilder, Rejected, SortOrder,
+};
+use crate::select::HitList;
 
 #[derive(Debug, Parser)]
 #[clap(author, version, about = "grep and edit git-tracked files in bulk", long_about = None)]
 struct Args {
-    #[clap(help = "Pattern to search")]
-    pattern: String,
+    #[clap(
+        help = "Pattern to search",
+        required_unless_present_any = ["input", "suggestions", "apply_halfdiff"]
+    )]
+    pattern: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["pattern", "code_only", "comments_only", "strings_only"],
+        help = "Read hit locations from <FILE> instead of searching (\"-\" for stdin)"
+    )]
+    input: Option<String>,
+
+    #[clap(
+        value_enum,
+        long = "input-format",
+        value_name = "FORMAT",
+        default_value = "auto",
+        help = "Format of --input; \"list\" for path:line[:col] lines and quickfix, \"json\" for cargo/rustc diagnostics and rg/ast-grep matches"
+    )]
+    input_format: InputFormat,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["pattern", "input", "code_only", "comments_only", "strings_only"],
+        help = "Pre-fill machine-applicable suggestions in <FILE> (cargo/rustc JSON or SARIF; \"-\" for stdin)"
+    )]
+    suggestions: Option<String>,
 
     #[clap(flatten)]
     grep_opts: GrepOptions,
@@ -29,6 +64,112 @@ struct Args {
     #[clap(short, long, help = "Show matches and exit")]
     preview: bool,
 
+    #[clap(long, help = "Prune the hits in a list before editing them")]
+    select: bool,
+
+    #[clap(
+        long = "group-identical",
+        help = "Show identical hunks once and apply the edit to all of them"
+    )]
+    group_identical: bool,
+
+    #[clap(
+        value_enum,
+        long,
+        value_name = "ORDER",
+        default_value = "file",
+        help = "Order of hunks in the halfdiff; \"similarity\" puts hunks with similar content next to each other"
+    )]
+    sort: SortOrder,
+
+    #[clap(
+        long = "flat",
+        help = "Put the path in every hunk marker instead of headers, so that hunks can be moved freely"
+    )]
+    flat: bool,
+
+    #[clap(
+        long = "3way",
+        help = "Fall back to a three-way merge with the files as they were when the patch doesn't apply, leaving conflict markers"
+    )]
+    three_way: bool,
+
+    #[clap(
+        long,
+        conflicts_with_all = ["index", "three_way"],
+        help = "Apply the patch to the index only, leaving the work tree untouched"
+    )]
+    cached: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "three_way",
+        help = "Apply the patch to both the work tree and the index"
+    )]
+    index: bool,
+
+    #[clap(
+        long,
+        requires = "message",
+        conflicts_with = "three_way",
+        help = "Commit the patch on top of HEAD, leaving out the other changes in the work tree and the index"
+    )]
+    commit: bool,
+
+    #[clap(
+        short,
+        long,
+        value_name = "MSG",
+        requires = "commit",
+        help = "Use <MSG> as the commit message of --commit"
+    )]
+    message: Option<String>,
+
+    #[clap(
+        long,
+        conflicts_with_all = ["three_way", "commit", "check", "emit_patch"],
+        help = "Apply the hunks that apply cleanly, and leave the rest in the halfdiff to edit again"
+    )]
+    reject: bool,
+
+    #[clap(
+        long = "check",
+        help = "Check if the patch applies with \"git apply --check\" instead of applying it"
+    )]
+    check: bool,
+
+    #[clap(
+        long = "emit-patch",
+        value_name = "FILE",
+        help = "Write the patch to <FILE> (\"-\" for stdout) instead of applying it"
+    )]
+    emit_patch: Option<String>,
+
+    #[clap(
+        long = "export-halfdiff",
+        value_name = "FILE",
+        conflicts_with = "preview",
+        help = "Write the halfdiff to <FILE> and a snapshot of the hunks to <FILE>.json instead of editing it"
+    )]
+    export_halfdiff: Option<String>,
+
+    #[clap(
+        long = "apply-halfdiff",
+        value_name = "FILE",
+        conflicts_with_all = ["pattern", "input", "suggestions", "select", "preview", "export_halfdiff"],
+        help = "Apply the halfdiff in <FILE> written by --export-halfdiff, without searching or editing"
+    )]
+    apply_halfdiff: Option<String>,
+
+    #[clap(
+        value_enum,
+        long,
+        value_name = "WHAT",
+        value_delimiter = ',',
+        help = "Annotate hunk markers with the enclosing function and/or the latest change in git blame (comma-separated)"
+    )]
+    annotate: Vec<Annotation>,
+
     #[clap(
         long,
         value_name = "MARKER",
@@ -63,19 +204,174 @@ fn arg_or_env_or_default(arg: &Option<String>, env: &str, default: &str) -> Stri
     default.to_string()
 }
 
+// parses the edit result and applies the patch, or checks or writes it out instead. the edits are
+// merged with --3way if the patch doesn't apply, or applied in part with --reject
+fn apply(args: &Args, git: &Git, builder: &mut PatchBuilder, buf: &[u8]) -> Result<()> {
+    let mut options = Vec::new();
+    if args.cached {
+        options.push("--cached");
+    } else if args.index {
+        options.push("--index");
+    }
+    if args.reject {
+        return builder.apply_with_rejects(git, buf, &options);
+    }
+
+    let dry_run = args.check || args.emit_patch.is_some();
+    let result = builder.parse_halfdiff(buf).and_then(|patch| {
+        if let Some(path) = &args.emit_patch {
+            match path.as_str() {
+                "-" => print!("{patch}"),
+                _ => std::fs::write(path, &patch)
+                    .with_context(|| format!("failed to write the patch to {path:?}. aborting."))?,
+            }
+        }
+        if args.check {
+            if patch.is_empty() {
+                eprintln!("no changes.");
+            } else {
+                let options = [options.as_slice(), &["--check", "--stat"]].concat();
+                eprint!("{}", git.apply(&patch, &options)?);
+                eprintln!("the patch applies cleanly.");
+            }
+        }
+        if dry_run || patch.is_empty() {
+            return Ok(());
+        }
+
+        // the index is also patched to keep it in step with the new commit. everything is checked
+        // before committing, so that a failure leaves nothing half done
+        let to_index = args.commit && options.is_empty();
+        if let Some(message) = args.message.as_deref().filter(|_| args.commit) {
+            git.apply(&patch, &[options.as_slice(), &["--check"]].concat())?;
+            if to_index {
+                git.apply(&patch, &["--cached", "--check"])?;
+            }
+            git.commit_patch(&patch, message)?;
+        }
+        git.apply(&patch, &options)?;
+        if to_index {
+            git.apply(&patch, &["--cached"])?;
+        }
+        Ok(())
+    });
+
+    let Err(e) = result else {
+        return Ok(());
+    };
+    if !args.three_way || dry_run || e.downcast_ref::<Diagnostics>().is_some() {
+        return Err(e);
+    }
+
+    eprintln!("failed to apply the patch directly. falling back to three-way merge.");
+    builder.merge_halfdiff(git, buf)
+}
+
+// the snapshot of the hunks is saved next to the exported halfdiff
+fn snapshot_path(path: &str) -> String {
+    format!("{path}.json")
+}
+
+fn export_halfdiff(builder: &PatchBuilder, path: &str) -> Result<()> {
+    let mut buf = Vec::new();
+    builder.write_halfdiff(&mut buf)?;
+    save_halfdiff(builder, path, &buf)
+}
+
+fn save_halfdiff(builder: &PatchBuilder, path: &str, buf: &[u8]) -> Result<()> {
+    std::fs::write(path, buf)
+        .with_context(|| format!("failed to write the halfdiff to {path:?}. aborting."))?;
+
+    let snapshot = snapshot_path(path);
+    std::fs::write(&snapshot, builder.to_snapshot().to_string())
+        .with_context(|| format!("failed to write the snapshot to {snapshot:?}. aborting."))
+}
+
+fn apply_halfdiff(args: &Args, git: &Git, path: &str) -> Result<()> {
+    let snapshot = snapshot_path(path);
+    let snapshot = std::fs::read_to_string(&snapshot)
+        .with_context(|| format!("failed to read the snapshot {snapshot:?}. aborting."))?;
+    let snapshot = serde_json::from_str(&snapshot)
+        .context("failed to parse the snapshot as JSON. aborting.")?;
+    let mut builder = PatchBuilder::from_snapshot(&snapshot)?;
+
+    let buf = std::fs::read(path).with_context(|| format!("failed to read {path:?}. aborting."))?;
+    let result = apply(args, git, &mut builder, &buf);
+
+    // the rejected hunks are saved in place of the halfdiff for another round
+    if let Err(e) = &result
+        && let Some(rejected) = e.downcast_ref::<Rejected>()
+    {
+        save_halfdiff(&builder, path, &rejected.halfdiff)?;
+    }
+    result
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    // create git object, run git-grep to collect matches
+    // create git object, run git-grep (or read the locations) to collect matches
     let git = Git::new()?;
-    let hunks = Hunks::collect(&git, &args.pattern, &args.grep_opts, &args.hunk_opts)?;
+    if let Some(path) = &args.apply_halfdiff {
+        return apply_halfdiff(&args, &git, path);
+    }
+
+    let mut replacements = Vec::new();
+    let mut matches = if let Some(suggestions) = &args.suggestions {
+        replacements = locations::parse_suggestions(&locations::read_input(suggestions)?)?;
+        let locations: Vec<_> = replacements.iter().map(|x| x.location()).collect();
+        Hunks::locate(&git, &locations, &args.grep_opts, &args.hunk_opts)?
+    } else if let Some(input) = &args.input {
+        let locations =
+            locations::parse_locations(&locations::read_input(input)?, args.input_format)?;
+        Hunks::locate(&git, &locations, &args.grep_opts, &args.hunk_opts)?
+    } else {
+        let pattern = args.pattern.as_deref().unwrap();
+        Hunks::search(&git, pattern, &args.grep_opts, &args.hunk_opts)?
+    };
+
+    // let the user prune the hits before building hunks
+    if args.select {
+        let list = HitList::from_matches(&matches)?;
+
+        let mut editor = Editor::new(
+            &arg_or_env_or_default(&args.editor, "EDITOR", "vi"),
+            args.stdout,
+        )?;
+        {
+            let mut writer = BufWriter::new(&mut editor);
+            list.write_list(&mut writer)?;
+            writer
+                .flush()
+                .context("failed to flush the tempfile. aborting.")?;
+        }
+        editor.wait()?;
+
+        list.parse_list(&mut matches, editor.get_buf())?;
+    }
+
+    // extend the hits and read the lines
+    let mut hunks = Hunks::collect(&git, matches, &args.grep_opts, &args.hunk_opts)?;
+    hunks.apply_replacements(&replacements)?;
+    hunks.annotate(&git, &args.annotate)?;
 
     // parse the result
     let config = &HalfDiffConfig {
         header: args.header.as_deref(),
         hunk: args.hunk.as_deref(),
+        group_identical: args.group_identical,
+        sort: args.sort,
+        flat: args.flat,
     };
-    let builder = PatchBuilder::from_hunks(config, hunks)?;
+    let mut builder = PatchBuilder::from_hunks(config, hunks)?;
+    if args.three_way {
+        builder.take_snapshots()?;
+    }
+
+    // leave the editing to scripts or other tools, which --apply-halfdiff picks up later
+    if let Some(path) = &args.export_halfdiff {
+        return export_halfdiff(&builder, path);
+    }
 
     // convert the git-grep result (hit locations) into "halfdiff" that will be edited by the user
     if args.preview {
@@ -102,15 +398,57 @@ fn main() -> Result<()> {
             .context("failed to flush the tempfile. aborting.")?;
     }
 
-    // wait for the user...
-    editor.wait()?;
+    let mut action = 'e';
+    loop {
+        // wait for the user...
+        if action == 'e' {
+            editor.wait()?;
+        }
+
+        // read the edit result, parse it into a unified diff, then apply the patch
+        let rebased = match action {
+            'b' => builder.rebase(),
+            _ => Ok(()),
+        };
+        let result = rebased.and_then(|_| apply(&args, &git, &mut builder, editor.get_buf()));
+        let Err(e) = result else {
+            break;
+        };
 
-    // read the edit result, and parse it into a unified diff
-    let patch = builder.parse_halfdiff(editor.get_buf())?;
+        // the conflicts are resolved in the files, as they have been written
+        if e.downcast_ref::<Conflicts>().is_some() {
+            return Err(e);
+        }
 
-    // then apply the patch
-    if !patch.is_empty() {
-        git.apply(&patch)?;
+        // let the user fix the edit result, with the errors inserted as comments. the edits can be
+        // rebased if the files have been changed while editing. the rejected hunks replace the
+        // edit result, having the errors already
+        eprintln!("Error: {e:?}");
+        let rejected = e.downcast_ref::<Rejected>();
+        if let Some(rejected) = rejected {
+            editor.reset(&rejected.halfdiff)?;
+        }
+        let (message, choices) = match e.downcast_ref::<Changed>() {
+            Some(_) => ("[e]dit again, [r]etry, re[b]ase, or [a]bort? ", "erba"),
+            None => ("[e]dit again, [r]etry, or [a]bort? ", "era"),
+        };
+        action = loop {
+            match prompt(message).map(|x| x.chars().next()) {
+                Ok(Some(c)) if choices.contains(c) => break c,
+                Ok(_) => continue,
+                Err(_) => break 'a', // not interactive
+            }
+        };
+        match action {
+            'e' if rejected.is_none() => {
+                editor.reset(&builder.annotate_errors(editor.get_buf(), &e))?
+            }
+            'e' | 'r' | 'b' => {}
+            _ => {
+                let path = editor.keep()?;
+                return Err(anyhow!("the edit result is saved in {path:?}. aborting."));
+            }
+        }
     }
 
     // we've done all
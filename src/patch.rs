@@ -1,108 +1,426 @@
-use crate::hunks::Hunks;
+use crate::git::Git;
+use crate::hunks::{Hunks, LineEnding, hash_content};
 use anyhow::{Context, Result, anyhow};
-use std::collections::HashMap;
+use clap::ValueEnum;
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Write as FmtWrite;
 use std::io::Write;
 
+// the same marker as unified diffs, placed at the end of a hunk
+const NO_EOL_MARKER: &str = "\\ No newline at end of file";
+
+// bumped when the layout of the snapshots for --apply-halfdiff changes
+const SNAPSHOT_VERSION: u64 = 1;
+
+// edited lines and whether the last of them lacks the newline, keyed by the original locations as
+// (file id, position, length). insertions come before the hunk at the same position
+type Edits = BTreeMap<(usize, usize, usize), (Vec<String>, bool)>;
+
+// problems found in the edit result, with one-origin line numbers in the buffer
+#[derive(Debug)]
+pub struct Diagnostics(pub Vec<(usize, String)>);
+
+impl std::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "found {} problem(s) in the edit result. aborting.",
+            self.0.len()
+        )?;
+        for (line, message) in &self.0 {
+            write!(f, "\n  line {line}: {message}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+// edited files that have been changed since the hunks were collected
+#[derive(Debug)]
+pub struct Changed(pub Vec<String>);
+
+impl std::fmt::Display for Changed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "found {} file(s) changed since the hunks were collected. aborting.",
+            self.0.len()
+        )?;
+        for path in &self.0 {
+            write!(f, "\n  {path}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Changed {}
+
+// conflicts left in the files by the three-way merge, which are written already
+#[derive(Debug)]
+pub struct Conflicts(pub Vec<String>);
+
+impl std::fmt::Display for Conflicts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "found {} conflict(s) left by the three-way merge. aborting.",
+            self.0.len()
+        )?;
+        for location in &self.0 {
+            write!(f, "\n  {location}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Conflicts {}
+
+// hunks that failed to apply with --reject, while the others have been applied. the halfdiff has
+// the failed hunks only, with the errors as comments
+#[derive(Debug)]
+pub struct Rejected {
+    pub locations: Vec<String>,
+    pub halfdiff: Vec<u8>,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "failed to apply {} hunk(s), while the others have been applied. aborting.",
+            self.locations.len()
+        )?;
+        for location in &self.locations {
+            write!(f, "\n  {location}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+// the hunk maps as lists, sorted to keep the snapshots stable
+type KeyEntries<V> = Vec<(usize, usize, V)>;
+
+fn key_entries<V: Clone>(map: &HashMap<(usize, usize), V>) -> KeyEntries<V> {
+    let mut entries: Vec<_> = map.iter().map(|(x, y)| (x.0, x.1, y.clone())).collect();
+    entries.sort_by_key(|x| (x.0, x.1));
+    entries
+}
+
+fn from_key_entries<V>(entries: KeyEntries<V>) -> HashMap<(usize, usize), V> {
+    entries.into_iter().map(|(x, y, z)| ((x, y), z)).collect()
+}
+
+// moves the hunks in the given files to the new keys, dropping the ones without
+fn move_keys<V>(
+    map: &mut HashMap<(usize, usize), V>,
+    ids: &HashSet<usize>,
+    moves: &HashMap<(usize, usize), (usize, usize)>,
+) {
+    *map = std::mem::take(map)
+        .into_iter()
+        .filter_map(|(key, value)| {
+            if ids.contains(&key.0) {
+                Some((*moves.get(&key)?, value))
+            } else {
+                Some((key, value))
+            }
+        })
+        .collect();
+}
+
+// parses "pos,len" of hunk markers into the zero-origin position and the length
+fn parse_location(marker: &str) -> Option<(usize, usize)> {
+    let (pos, len) = marker.split_once(',')?;
+    let pos = pos.trim().parse::<usize>().ok()?.checked_sub(1)?;
+    Some((pos, len.trim().parse().ok()?))
+}
+
 struct LineAccumulator<'a, 'b> {
     id: usize,
-    hunk: &'a str,
-    buf: String,
-    edited_len: usize,
-    pos_diff: isize,
-    original: &'b HashMap<(usize, usize), Vec<String>>,
+    hunk: Option<(usize, usize)>, // original position and length
+    inserted: bool,
+    line: usize, // of the hunk marker
+    buf: Vec<&'a str>,
+    context: Vec<&'a str>,
+    new_files: Vec<&'a str>, // numbered after the files in the builder
+    seen: HashMap<(usize, usize, usize), usize>,
+    broken_file: bool,
+    skip: bool, // lines are ignored until the next marker, as the error is already reported
+    diagnostics: Vec<(usize, String)>,
+    builder: &'b PatchBuilder,
 }
 
 impl<'a, 'b> LineAccumulator<'a, 'b> {
-    fn new(original: &'b HashMap<(usize, usize), Vec<String>>) -> Self {
+    fn new(builder: &'b PatchBuilder) -> Self {
         LineAccumulator {
             id: usize::MAX,
-            hunk: "",
-            buf: String::new(),
-            edited_len: 0,
-            pos_diff: 0,
-            original,
+            hunk: None,
+            inserted: false,
+            line: 0,
+            buf: Vec::new(),
+            context: Vec::new(),
+            new_files: Vec::new(),
+            seen: HashMap::new(),
+            broken_file: false,
+            skip: false,
+            diagnostics: Vec::new(),
+            builder,
         }
     }
 
     fn is_empty(&self) -> bool {
-        self.id == usize::MAX || self.hunk.is_empty()
+        self.hunk.is_none()
     }
 
-    fn open_new_file(&mut self, id: usize) {
-        self.id = id;
+    fn report(&mut self, line: usize, result: Result<()>) {
+        if let Err(e) = result {
+            self.diagnostics.push((line, e.to_string()));
+        }
     }
 
-    fn open_new_hunk(&mut self, hunk: &'a str) {
-        self.hunk = hunk;
-        self.buf.clear();
-        self.edited_len = 0;
+    fn open_file(&mut self, line: usize, filename: &'a str) {
+        self.close_hunk();
+
+        let result = self.find_file(filename);
+        self.broken_file = result.is_err();
+        self.skip = self.broken_file;
+        if self.broken_file {
+            self.id = usize::MAX;
+        }
+        self.report(line, result);
+    }
+
+    fn find_file(&mut self, filename: &'a str) -> Result<()> {
+        if let Some(id) = self.builder.files.get(filename) {
+            self.id = *id;
+        } else if filename.is_empty() {
+            return Err(anyhow!("got an invalid filename {filename:?}"));
+        } else if std::path::Path::new(filename).exists() {
+            return Err(anyhow!(
+                "got a filename {filename:?} without hits; only files that don't exist can be added"
+            ));
+        } else {
+            self.open_created_file(filename);
+        }
+        Ok(())
     }
 
-    fn push_line(&mut self, line: &str) {
-        assert!(!self.is_empty());
+    fn open_created_file(&mut self, filename: &'a str) {
+        let i = self
+            .new_files
+            .iter()
+            .position(|x| *x == filename)
+            .unwrap_or_else(|| {
+                self.new_files.push(filename);
+                self.new_files.len() - 1
+            });
+        self.id = self.builder.files.len() + i;
+    }
 
-        self.buf.push_str(line);
-        self.buf.push('\n');
-        self.edited_len += 1;
+    fn is_created_file(&self) -> bool {
+        let n = self.builder.files.len();
+        (n..n + self.new_files.len()).contains(&self.id)
     }
 
-    fn is_edited(&self, original_lines: &[String]) -> bool {
-        for (o, t) in self
-            .buf
-            .lines()
-            .chain(std::iter::repeat(""))
-            .zip(original_lines.iter())
-        {
-            if o != t {
-                return true;
+    fn open_new_hunk(&mut self, line: usize, marker: &'a str) {
+        self.close_hunk();
+        self.line = line;
+
+        // hunks in broken files are skipped as well
+        let result = self.find_hunk(line, marker);
+        self.skip = result.is_err() || self.hunk.is_none();
+        self.report(line, result);
+    }
+
+    fn find_hunk(&mut self, line: usize, marker: &'a str) -> Result<()> {
+        // the path precedes the location in the flat format
+        let marker = match marker.rsplit_once(':') {
+            Some((filename, marker)) if self.builder.flat => {
+                self.open_file(line, filename.trim());
+                marker.trim()
             }
+            _ => marker,
+        };
+        if self.id == usize::MAX {
+            return if self.broken_file {
+                Ok(())
+            } else {
+                Err(anyhow!("got a hunk marker before any header"))
+            };
         }
-        false
+
+        let (pos, len) = parse_location(marker)
+            .with_context(|| format!("got an invalid hunk marker {marker:?}"))?;
+        let key = (self.id, pos);
+
+        // hunks added by the user are pure insertions, without original lines. new files are
+        // written as a whole in a "1,0" hunk
+        let created = self.is_created_file();
+        let raw = self.builder.raw_hunks.get(&key);
+        self.inserted = match raw {
+            _ if created && (pos, len) != (0, 0) => {
+                return Err(anyhow!(
+                    "got a hunk marker {marker:?} in a new file, which only takes \"1,0\""
+                ));
+            }
+            Some(lines) if !created && lines.len() == len => false,
+            _ if len == 0 => {
+                self.check_insertion(pos)?;
+                true
+            }
+            _ => {
+                return Err(anyhow!(
+                    "got a hunk marker {marker:?} that doesn't match any original hunk; markers must not be edited"
+                ));
+            }
+        };
+
+        // hunks in a group are taken by the first of them
+        let members = match self.builder.groups.get(&key) {
+            Some(members) if !self.inserted => members.as_slice(),
+            _ => std::slice::from_ref(&key),
+        };
+        for &(id, pos) in members {
+            if let Some(first) = self.seen.insert((id, pos, len), line) {
+                return Err(anyhow!(
+                    "got a duplicated hunk marker {marker:?}, which is also at line {first}"
+                ));
+            }
+        }
+
+        self.hunk = Some((pos, len));
+        Ok(())
     }
 
-    fn dump_hunk(&mut self, acc: &mut HunkAccumulator) -> Result<()> {
-        if self.is_empty() {
-            // clear the state
-            self.open_new_hunk("");
-            return Ok(());
+    fn close_hunk(&mut self) {
+        self.hunk = None;
+        self.buf.clear();
+        self.context.clear();
+    }
+
+    fn push_stray(&mut self, line: usize, text: &str) {
+        // blank lines outside hunks are harmless
+        if !self.skip && !text.trim().is_empty() {
+            self.report(line, Err(anyhow!("got text {text:?} outside hunks")));
+        }
+    }
+
+    // the edit result is split at LF only, so that a stray '\r' in an LF file is kept. CRLF files
+    // have it as a part of the terminator
+    fn strip_cr(&self, line: &'a str) -> &'a str {
+        let crlf = self.builder.line_endings.get(self.id) == Some(&LineEnding::Crlf);
+        match line.strip_suffix('\r') {
+            Some(x) if crlf || x == NO_EOL_MARKER => x,
+            _ => line,
         }
+    }
 
-        let hunk: Vec<_> = self.hunk.split(',').collect();
-        let original_pos = hunk[0].parse::<usize>().unwrap() - 1;
-        let original_lines = self.original.get(&(self.id, original_pos)).unwrap();
+    fn push_line(&mut self, line: &'a str) {
+        self.buf.push(self.strip_cr(line));
+    }
 
-        if !self.is_edited(original_lines) {
-            // clear the state
-            self.open_new_hunk("");
-            return Ok(());
+    fn push_context(&mut self, line: &'a str) {
+        self.context.push(self.strip_cr(line));
+    }
+
+    fn check_insertion(&self, pos: usize) -> Result<()> {
+        // inserted lines must not split the original lines of a hunk
+        let overlaps = self
+            .builder
+            .raw_hunks
+            .iter()
+            .any(|(&(i, p), lines)| i == self.id && p < pos && pos < p + lines.len());
+        if overlaps {
+            return Err(anyhow!(
+                "got a hunk marker \"{},0\" inserting lines into another hunk",
+                pos + 1
+            ));
         }
+        Ok(())
+    }
 
-        let mut buf = String::new();
-        writeln!(
-            &mut buf,
-            "@@ -{},{} +{},{} @@",
-            original_pos,
-            original_lines.len(),
-            (original_pos as isize + self.pos_diff) as usize,
-            self.edited_len
-        )?;
-        for l in original_lines {
-            buf.push('-');
-            buf.push_str(l);
-            buf.push('\n');
+    fn check_context(&self, context: Option<&(Vec<String>, Vec<String>)>) -> Result<()> {
+        // context lines may be deleted, but not modified nor added
+        let mut expected = context
+            .into_iter()
+            .flat_map(|(before, after)| before.iter().chain(after));
+        for line in &self.context {
+            if !expected.any(|x| x == line) {
+                return Err(anyhow!("got a modified context line {line:?}"));
+            }
         }
-        for l in self.buf.lines() {
-            buf.push('+');
-            buf.push_str(l);
-            buf.push('\n');
+        Ok(())
+    }
+
+    fn is_edited(&self, original_lines: &[String]) -> bool {
+        self.buf.len() != original_lines.len()
+            || self
+                .buf
+                .iter()
+                .zip(original_lines.iter())
+                .any(|(o, t)| o != t)
+    }
+
+    fn dump_hunk(&mut self, edits: &mut Edits) {
+        let result = self.collect_edit(edits);
+        self.report(self.line, result);
+        self.close_hunk();
+    }
+
+    fn collect_edit(&mut self, edits: &mut Edits) -> Result<()> {
+        let Some((original_pos, original_len)) = self.hunk else {
+            return Ok(());
+        };
+        let key = (self.id, original_pos);
+        let inserted = self.inserted;
+
+        let original_lines: &[String] = if inserted {
+            &[]
+        } else {
+            &self.builder.raw_hunks[&key]
+        };
+        let context = if inserted {
+            None
+        } else {
+            self.builder.context.get(&key)
+        };
+        self.check_context(context)?;
+
+        // the final newline can be toggled only at the end of file
+        let eof_newline = &self.builder.eof_newline;
+        let at_eof = if inserted {
+            self.is_created_file()
+        } else {
+            eof_newline.contains_key(&key)
+        };
+        let original_no_eol = !inserted && eof_newline.get(&key) == Some(&false);
+        let no_eol = self.buf.last() == Some(&NO_EOL_MARKER);
+        if no_eol {
+            self.buf.pop();
+            if !at_eof || self.buf.is_empty() {
+                return Err(anyhow!(
+                    "got {NO_EOL_MARKER:?} in a hunk that doesn't end the file"
+                ));
+            }
         }
-        acc.push_hunk(buf.as_str());
 
-        self.pos_diff += self.edited_len as isize;
-        self.pos_diff -= original_lines.len() as isize;
-        self.open_new_hunk("");
+        if !self.is_edited(original_lines) && no_eol == original_no_eol {
+            return Ok(());
+        }
 
+        // replay the edit onto all the identical hunks if grouped
+        let members = match self.builder.groups.get(&key) {
+            Some(members) if !inserted => members.as_slice(),
+            _ => std::slice::from_ref(&key),
+        };
+        for &(id, pos) in members {
+            let lines = self.buf.iter().map(|x| x.to_string()).collect();
+            edits.insert((id, pos, original_len), (lines, no_eol));
+        }
         Ok(())
     }
 }
@@ -110,6 +428,8 @@ impl<'a, 'b> LineAccumulator<'a, 'b> {
 struct HunkAccumulator {
     header_len: usize,
     buf: String,
+    pos_diff: isize,
+    eol: &'static str,
 }
 
 impl HunkAccumulator {
@@ -117,6 +437,8 @@ impl HunkAccumulator {
         HunkAccumulator {
             buf: String::new(),
             header_len: 0,
+            pos_diff: 0,
+            eol: "\n",
         }
     }
 
@@ -124,14 +446,57 @@ impl HunkAccumulator {
         self.header_len == self.buf.len()
     }
 
-    fn open_new_patch(&mut self, filename: &str) {
-        let header = format!("--- a/{filename}\n+++ b/{filename}\n");
+    fn open_new_patch(&mut self, filename: &str, line_ending: LineEnding, created: bool) {
+        let header = if created {
+            format!("--- /dev/null\n+++ b/{filename}\n")
+        } else {
+            format!("--- a/{filename}\n+++ b/{filename}\n")
+        };
         self.header_len = header.len();
         self.buf = header;
+        self.pos_diff = 0;
+        self.eol = line_ending.as_str();
     }
 
-    fn push_hunk(&mut self, hunk: &str) {
-        self.buf.push_str(hunk);
+    fn push_lines(&mut self, sign: char, lines: &[String], no_eol: bool) {
+        for (i, l) in lines.iter().enumerate() {
+            self.buf.push(sign);
+            self.buf.push_str(l);
+            if no_eol && i + 1 == lines.len() {
+                self.buf.push('\n');
+                self.buf.push_str(NO_EOL_MARKER);
+                self.buf.push('\n');
+            } else {
+                self.buf.push_str(self.eol);
+            }
+        }
+    }
+
+    fn push_hunk(
+        &mut self,
+        original_pos: usize,
+        (original_lines, original_no_eol): (&[String], bool),
+        (edited_lines, edited_no_eol): (&[String], bool),
+    ) -> Result<()> {
+        // positions in unified diffs are one-origin, except that empty ranges point at the line
+        // before them
+        let unidiff_pos = |pos: usize, len: usize| if len == 0 { pos } else { pos + 1 };
+        let edited_pos = (original_pos as isize + self.pos_diff) as usize;
+
+        writeln!(
+            &mut self.buf,
+            "@@ -{},{} +{},{} @@",
+            unidiff_pos(original_pos, original_lines.len()),
+            original_lines.len(),
+            unidiff_pos(edited_pos, edited_lines.len()),
+            edited_lines.len()
+        )?;
+        self.push_lines('-', original_lines, original_no_eol);
+        self.push_lines('+', edited_lines, edited_no_eol);
+
+        self.pos_diff += edited_lines.len() as isize;
+        self.pos_diff -= original_lines.len() as isize;
+        Ok(())
     }
 
     fn dump_patch(&mut self, acc: &mut String) {
@@ -144,18 +509,60 @@ impl HunkAccumulator {
     }
 }
 
+#[derive(Copy, Clone, Debug, Default, ValueEnum)]
+pub enum SortOrder {
+    #[default]
+    File,
+    Content,
+    Similarity,
+}
+
+#[derive(Default)]
 pub struct HalfDiffConfig<'a> {
     pub header: Option<&'a str>,
     pub hunk: Option<&'a str>,
+    pub group_identical: bool,
+    pub sort: SortOrder,
+    pub flat: bool,
+}
+
+// character-wise Levenshtein distance
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut curr = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        curr[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let subst = prev[j] + usize::from(ca != cb);
+            curr[j + 1] = subst.min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev[b.len()]
 }
 
 pub struct PatchBuilder {
     header_marker: String,
     hunk_marker: String,
+    context_marker: String,
+    error_marker: String,
     header_collision_avoidance: bool,
     hunk_collision_avoidance: bool,
     files: HashMap<String, usize>,
     raw_hunks: HashMap<(usize, usize), Vec<String>>,
+    prefill: HashMap<(usize, usize), Vec<String>>,
+    groups: HashMap<(usize, usize), Vec<(usize, usize)>>, // the first member to all members
+    line_endings: Vec<LineEnding>,
+    hashes: Vec<Option<u64>>,
+    rebased: HashMap<(usize, usize), usize>, // the current locations of the hunks in rebased files
+    rebased_files: HashSet<usize>,
+    snapshots: HashMap<usize, Vec<u8>>, // the base of three-way merges
+    eof_newline: HashMap<(usize, usize), bool>,
+    context: HashMap<(usize, usize), (Vec<String>, Vec<String>)>,
+    annotations: HashMap<(usize, usize), Vec<String>>,
+    notes: Vec<String>, // the error lines written in the last halfdiff, dropped when parsing
+    sort: SortOrder,
+    flat: bool,
 }
 
 impl PatchBuilder {
@@ -166,6 +573,8 @@ impl PatchBuilder {
         let mut locs = PatchBuilder {
             header_marker,
             hunk_marker,
+            context_marker: "::".to_string(),
+            error_marker: "!!".to_string(),
             header_collision_avoidance: config.header.is_none(),
             hunk_collision_avoidance: config.hunk.is_none(),
             files: hunks
@@ -179,14 +588,89 @@ impl PatchBuilder {
                 .into_iter()
                 .map(|(x, y, z)| ((x, y), z))
                 .collect(),
+            prefill: hunks.prefill,
+            groups: HashMap::new(),
+            line_endings: hunks.line_endings,
+            hashes: hunks.hashes,
+            rebased: HashMap::new(),
+            rebased_files: HashSet::new(),
+            snapshots: HashMap::new(),
+            eof_newline: hunks.eof_newline,
+            context: hunks.context,
+            annotations: hunks.annotations,
+            notes: Vec::new(),
+            sort: config.sort,
+            flat: config.flat,
         };
 
+        if config.group_identical {
+            locs.group_identical();
+        }
         locs.avoid_collision()?;
         Ok(locs)
     }
 
+    fn group_identical(&mut self) {
+        let mut keys: Vec<_> = self.raw_hunks.keys().copied().collect();
+        keys.sort();
+
+        // hunks are identical if the original and the pre-filled lines, and the final newline are
+        // the same
+        let mut bins: HashMap<_, Vec<(usize, usize)>> = HashMap::new();
+        for key in keys {
+            let content = (
+                &self.raw_hunks[&key],
+                self.prefill.get(&key),
+                self.eof_newline.get(&key),
+            );
+            bins.entry(content).or_default().push(key);
+        }
+
+        self.groups = bins
+            .into_values()
+            .filter(|x| x.len() > 1)
+            .map(|x| (x[0], x))
+            .collect();
+    }
+
+    fn shown_lines(&self, key: &(usize, usize)) -> &[String] {
+        self.prefill.get(key).unwrap_or(&self.raw_hunks[key])
+    }
+
+    fn sort_keys(&self, keys: &mut Vec<&(usize, usize)>) {
+        // keys come in the file order; ties keep it
+        match self.sort {
+            SortOrder::File => {}
+            SortOrder::Content => keys.sort_by_key(|x| self.shown_lines(x)),
+            SortOrder::Similarity => {
+                // chain each hunk to its nearest unvisited neighbour, starting from the first one
+                let texts: Vec<Vec<char>> = keys
+                    .iter()
+                    .map(|x| self.shown_lines(x).join("\n").chars().collect())
+                    .collect();
+
+                let mut rest: Vec<usize> = (1..keys.len()).collect();
+                let mut order = Vec::with_capacity(keys.len());
+                if !keys.is_empty() {
+                    order.push(0);
+                }
+                while !rest.is_empty() {
+                    let last = &texts[*order.last().unwrap()];
+                    let (i, _) = rest
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, x)| edit_distance(last, &texts[**x]))
+                        .unwrap();
+                    order.push(rest.remove(i));
+                }
+
+                *keys = order.into_iter().map(|i| keys[i]).collect();
+            }
+        }
+    }
+
     fn scan_lines(&self, marker: &str) -> bool {
-        for lines in self.raw_hunks.values() {
+        for lines in self.raw_hunks.values().chain(self.prefill.values()) {
             for line in lines {
                 if line.starts_with(marker) {
                     return true;
@@ -196,36 +680,40 @@ impl PatchBuilder {
         false
     }
 
-    fn avoid_collision(&mut self) -> Result<()> {
-        // header
+    fn extend_marker(&self, marker: &str, c: char, avoidance: bool, name: &str) -> Result<String> {
+        let mut marker = marker.to_string();
         for i in 0..17 {
-            if !self.scan_lines(&self.header_marker) {
+            if !self.scan_lines(&marker) {
                 break;
             }
-            if i == 16 || !self.header_collision_avoidance {
+            if i == 16 || !avoidance {
                 return Err(anyhow!(
-                    "failed to avoid collision with the header marker {:?}. aborting.",
-                    self.header_marker
+                    "failed to avoid collision with the {name} marker {marker:?}. aborting."
                 ));
             }
 
-            self.header_marker.push('+');
+            marker.push(c);
         }
+        Ok(marker)
+    }
 
-        // hunk
-        for i in 0..17 {
-            if !self.scan_lines(&self.hunk_marker) {
-                break;
-            }
-            if i == 16 || !self.hunk_collision_avoidance {
-                return Err(anyhow!(
-                    "failed to avoid collision with the hunk marker {:?}. aborting.",
-                    self.hunk_marker
-                ));
-            }
-
-            self.hunk_marker.push('@');
+    fn avoid_collision(&mut self) -> Result<()> {
+        self.header_marker = self.extend_marker(
+            &self.header_marker,
+            '+',
+            self.header_collision_avoidance,
+            "header",
+        )?;
+        self.hunk_marker = self.extend_marker(
+            &self.hunk_marker,
+            '@',
+            self.hunk_collision_avoidance,
+            "hunk",
+        )?;
+        if !self.context.is_empty() {
+            self.context_marker = self.extend_marker(&self.context_marker, ':', true, "context")?;
         }
+        self.error_marker = self.extend_marker(&self.error_marker, '!', true, "error")?;
         Ok(())
     }
 
@@ -233,25 +721,51 @@ impl PatchBuilder {
         // index files
         let index: HashMap<usize, &str> = self.files.iter().map(|x| (*x.1, x.0.as_str())).collect();
 
+        // hunks grouped into another are not shown
+        let hidden: HashSet<_> = self.groups.values().flat_map(|x| &x[1..]).collect();
+
         // format and dump file content
-        let mut keys: Vec<_> = self.raw_hunks.keys().collect();
+        let mut keys: Vec<_> = self
+            .raw_hunks
+            .keys()
+            .filter(|x| !hidden.contains(x))
+            .collect();
         keys.sort();
+        self.sort_keys(&mut keys);
 
+        // the header is repeated whenever the file changes, as hunks may be sorted across files.
+        // the flat format has the path in every hunk marker instead
         let mut prev_id = usize::MAX;
         for &(id, pos) in keys {
-            if prev_id != id {
-                let filename = index.get(&id).unwrap();
+            let filename = index.get(&id).unwrap();
+            if prev_id != id && !self.flat {
                 drain.write_all(format!("{} {}\n", self.header_marker, filename).as_bytes())?;
                 prev_id = id;
             }
 
             let lines = self.raw_hunks.get(&(id, pos)).unwrap();
 
-            let mut acc = format!("{} {},{}\n", self.hunk_marker, pos + 1, lines.len());
-            for line in lines {
-                acc.push_str(line);
-                acc.push('\n');
+            // the marker tells the original location even if the hunk is pre-filled
+            let mut notes = self
+                .annotations
+                .get(&(id, pos))
+                .cloned()
+                .unwrap_or_default();
+            if let Some(members) = self.groups.get(&(id, pos)) {
+                let locations: Vec<_> = members[1..]
+                    .iter()
+                    .map(|(id, pos)| format!("{}:{}", index[id], pos + 1))
+                    .collect();
+                notes.push(format!("also at {}", locations.join(", ")));
             }
+            let no_eol = self.eof_newline.get(&(id, pos)) == Some(&false);
+            let acc = self.format_hunk(
+                filename,
+                (id, pos, lines.len()),
+                self.shown_lines(&(id, pos)),
+                no_eol,
+                &notes,
+            )?;
 
             drain.write_all(acc.as_bytes())?;
         }
@@ -259,36 +773,1418 @@ impl PatchBuilder {
         Ok(())
     }
 
+    // formats a hunk of the halfdiff, with the notes appended to the marker. context lines are
+    // prefixed by the marker and a space, except for empty ones
+    fn format_hunk(
+        &self,
+        filename: &str,
+        (id, pos, len): (usize, usize, usize),
+        lines: &[String],
+        no_eol: bool,
+        notes: &[String],
+    ) -> Result<String> {
+        let mut acc = format!("{} ", self.hunk_marker);
+        if self.flat {
+            write!(&mut acc, "{filename}:")?;
+        }
+        write!(&mut acc, "{},{}", pos + 1, len)?;
+        for note in notes {
+            write!(&mut acc, " {} {}", self.hunk_marker, note)?;
+        }
+        acc.push('\n');
+
+        // lines inserted by the user have no context of their own
+        let (before, after) = match len {
+            0 => (&[][..], &[][..]),
+            _ => self
+                .context
+                .get(&(id, pos))
+                .map_or((&[][..], &[][..]), |x| (&x.0[..], &x.1[..])),
+        };
+        let context = |acc: &mut String, line: &str| {
+            acc.push_str(&self.context_marker);
+            if !line.is_empty() {
+                acc.push(' ');
+                acc.push_str(line);
+            }
+            acc.push('\n');
+        };
+        for line in before {
+            context(&mut acc, line);
+        }
+        for line in lines {
+            acc.push_str(line);
+            acc.push('\n');
+        }
+        if no_eol {
+            acc.push_str(NO_EOL_MARKER);
+            acc.push('\n');
+        }
+        for line in after {
+            context(&mut acc, line);
+        }
+        Ok(acc)
+    }
+
+    // inserts the errors as comment lines before the offending lines of the edit result, in place
+    // of the ones from the previous round
+    pub fn annotate_errors(&mut self, buf: &[u8], error: &anyhow::Error) -> Vec<u8> {
+        let diff = String::from_utf8_lossy(buf);
+        let lines: Vec<_> = diff.split_terminator('\n').collect();
+
+        // errors without locations go to the head
+        let mut notes = Vec::new();
+        for cause in error.chain() {
+            if let Some(Diagnostics(diagnostics)) = cause.downcast_ref::<Diagnostics>() {
+                notes.extend(diagnostics.iter().cloned());
+                continue;
+            }
+            if let Some(Changed(paths)) = cause.downcast_ref::<Changed>() {
+                for path in paths {
+                    let line = self.locate(&lines, path, usize::MAX);
+                    let message = format!("{path} has been changed since the hunks were collected");
+                    notes.push((line.unwrap_or(1), message));
+                }
+                continue;
+            }
+            for message in cause.to_string().lines() {
+                let line = message
+                    .strip_prefix("error: patch failed: ")
+                    .and_then(|x| x.rsplit_once(':'))
+                    .and_then(|(path, pos)| self.locate(&lines, path, pos.parse().ok()?));
+                notes.push((line.unwrap_or(1), message.to_string()));
+            }
+        }
+        notes.sort_by_key(|x| x.0);
+
+        let notes: Vec<_> = notes
+            .into_iter()
+            .map(|(i, message)| (i, format!("{} {}", self.error_marker, message)))
+            .collect();
+        let mut acc = String::new();
+        let mut iter = notes.iter().peekable();
+        let old_notes = self.error_notes(&lines);
+        for (i, l) in lines.iter().enumerate() {
+            while let Some((_, note)) = iter.next_if(|x| x.0 <= i + 1) {
+                acc.push_str(note);
+                acc.push('\n');
+            }
+            if !old_notes[i] {
+                acc.push_str(l);
+                acc.push('\n');
+            }
+        }
+        for (_, note) in iter {
+            acc.push_str(note);
+            acc.push('\n');
+        }
+        self.notes = notes.into_iter().map(|x| x.1).collect();
+        acc.into_bytes()
+    }
+
+    // tells the error lines written by us in the last halfdiff, each taken once. the other lines
+    // with the error marker are the content of hunks, even if they look the same
+    fn error_notes(&self, lines: &[&str]) -> Vec<bool> {
+        let mut left: HashMap<&str, usize> = HashMap::new();
+        for note in &self.notes {
+            *left.entry(note).or_default() += 1;
+        }
+        lines
+            .iter()
+            .map(|l| match left.get_mut(l) {
+                Some(n) if *n > 0 => {
+                    *n -= 1;
+                    true
+                }
+                _ => false,
+            })
+            .collect()
+    }
+
+    // finds the hunk marker for a one-origin position of the original file, as reported by
+    // "git apply". falls back to the first marker of the file
+    fn locate(&self, lines: &[&str], path: &str, pos: usize) -> Option<usize> {
+        let mut filename = "";
+        let mut first = None;
+        for (i, l) in lines.iter().enumerate() {
+            if l.starts_with(&self.header_marker) {
+                filename = l[self.header_marker.len()..].trim();
+            } else if l.starts_with(&self.hunk_marker) {
+                let mut hunk = self.strip_annotation(l);
+                if self.flat
+                    && let Some((x, y)) = hunk.rsplit_once(':')
+                {
+                    (filename, hunk) = (x.trim(), y.trim());
+                }
+                if filename != path {
+                    continue;
+                }
+                first.get_or_insert(i + 1);
+
+                // empty ranges point at the line before them in unified diffs
+                match parse_location(hunk) {
+                    Some((x, 0)) if x == pos => return Some(i + 1),
+                    Some((x, len)) if len > 0 && x + 1 == pos => return Some(i + 1),
+                    _ => {}
+                }
+            }
+        }
+        first
+    }
+
+    fn strip_annotation<'a>(&self, marker: &'a str) -> &'a str {
+        // annotations follow the location after another hunk marker
+        let body = &marker[self.hunk_marker.len()..];
+        let end = body
+            .find(&format!(" {}", self.hunk_marker))
+            .unwrap_or(body.len());
+        body[..end].trim()
+    }
+
     pub fn parse_halfdiff(&self, buf: &[u8]) -> Result<String> {
-        let mut patch = String::new();
-        let mut hunks = HunkAccumulator::new();
-        let mut lines = LineAccumulator::new(&self.raw_hunks);
+        let diff = std::str::from_utf8(buf)
+            .context("failed parse the edit result as a UTF-8 string. aborting.")?;
+        let (edits, new_files) = self.parse_edits(diff)?;
+        self.check_changed(&edits)?;
+        self.build_patch(&edits, &new_files)
+    }
 
+    // the files may have been changed while editing
+    fn check_changed(&self, edits: &Edits) -> Result<()> {
+        let mut ids: Vec<_> = edits.keys().map(|x| x.0).collect();
+        ids.dedup();
+        let changed = self.changed_files(&ids);
+        if !changed.is_empty() {
+            return Err(Changed(changed).into());
+        }
+        Ok(())
+    }
+
+    // applies the hunks that apply cleanly, and fails with the rest as a fresh halfdiff. the hunks
+    // left in the patched files are moved to the current locations, as if they were collected again
+    pub fn apply_with_rejects(&mut self, git: &Git, buf: &[u8], options: &[&str]) -> Result<()> {
         let diff = std::str::from_utf8(buf)
             .context("failed parse the edit result as a UTF-8 string. aborting.")?;
+        let (edits, new_files) = self.parse_edits(diff)?;
+        self.check_changed(&edits)?;
 
-        for l in diff.lines() {
-            if l.starts_with(&self.header_marker) {
-                lines.dump_hunk(&mut hunks)?;
-                hunks.dump_patch(&mut patch);
+        let patch = self.build_patch(&edits, &new_files)?;
+        if patch.is_empty() {
+            return Ok(());
+        }
+        let Err(error) = git.apply(&patch, options) else {
+            return Ok(());
+        };
+
+        // the hunks failing on their own are left out of the patch
+        let check = [options, &["--check"][..]].concat();
+        let (mut applied, mut rejected) = (Edits::new(), Vec::new());
+        for (&key, edit) in &edits {
+            let single = Edits::from([(key, edit.clone())]);
+            match self
+                .build_patch(&single, &new_files)
+                .and_then(|x| git.apply(&x, &check))
+            {
+                Ok(_) => {
+                    applied.insert(key, edit.clone());
+                }
+                Err(e) => rejected.push((key, edit.clone(), e.root_cause().to_string())),
+            }
+        }
+        if applied.is_empty() {
+            return Err(error);
+        }
+        git.apply(&self.build_patch(&applied, &new_files)?, options)?;
 
-                let filename = l[self.header_marker.len()..].trim();
-                let id = self.files.get(filename).with_context(|| {
-                    format!("got an invalid filename {filename:?} in the edit result. aborting.")
-                })?;
+        let mut comments = HashMap::new();
+        let mut left = Edits::new();
+        for (key, edit, message) in rejected {
+            let key = self.shifted(&applied, key);
+            comments.insert(key, message);
+            left.insert(key, edit);
+        }
+        self.settle_applied(&applied)?;
+
+        let halfdiff = self.write_edits(&left, &new_files, &comments)?;
+        let index = self.index(&new_files);
+        Err(Rejected {
+            locations: left
+                .keys()
+                .map(|(id, pos, _)| format!("{}:{}", index[id], pos + 1))
+                .collect(),
+            halfdiff,
+        }
+        .into())
+    }
+
+    // the paths by the file ids, including the files created by the user
+    fn index<'a>(&'a self, new_files: &[&'a str]) -> HashMap<usize, &'a str> {
+        let created = new_files.iter().enumerate();
+        self.files
+            .iter()
+            .map(|x| (*x.1, x.0.as_str()))
+            .chain(created.map(|(i, x)| (self.files.len() + i, *x)))
+            .collect()
+    }
 
-                hunks.open_new_patch(filename);
-                lines.open_new_file(*id);
+    // the location of a hunk in the file patched with the applied edits
+    fn shifted(
+        &self,
+        applied: &Edits,
+        (id, pos, len): (usize, usize, usize),
+    ) -> (usize, usize, usize) {
+        let current = match self.rebased.get(&(id, pos)) {
+            Some(&x) if len > 0 => x,
+            _ => pos,
+        };
+        let offset: isize = applied
+            .range((id, 0, 0)..(id, pos, len))
+            .map(|(x, y)| y.0.len() as isize - x.2 as isize)
+            .sum();
+        (id, current.saturating_add_signed(offset), len)
+    }
+
+    // forgets the applied hunks, and moves the others in the patched files to the current
+    // locations. groups are dissolved, as some of the members may have been applied
+    fn settle_applied(&mut self, applied: &Edits) -> Result<()> {
+        let ids: HashSet<_> = applied
+            .keys()
+            .map(|x| x.0)
+            .filter(|&x| x < self.files.len())
+            .collect();
+
+        let mut moves = HashMap::new();
+        for (&(id, pos), lines) in &self.raw_hunks {
+            let key = (id, pos, lines.len());
+            if ids.contains(&id) && !applied.contains_key(&key) {
+                let (_, current, _) = self.shifted(applied, key);
+                moves.insert((id, pos), (id, current));
+            }
+        }
+        move_keys(&mut self.raw_hunks, &ids, &moves);
+        move_keys(&mut self.prefill, &ids, &moves);
+        move_keys(&mut self.eof_newline, &ids, &moves);
+        move_keys(&mut self.context, &ids, &moves);
+        move_keys(&mut self.annotations, &ids, &moves);
+        self.rebased.retain(|x, _| !ids.contains(&x.0));
+        self.groups.clear();
+
+        // the applied edits are not changes made by others
+        for (path, &id) in &self.files {
+            if ids.contains(&id) && self.hashes[id].is_some() {
+                let content = std::fs::read(path)
+                    .with_context(|| format!("failed to read {path:?}. aborting."))?;
+                self.hashes[id] = Some(hash_content(&content));
+            }
+        }
+        Ok(())
+    }
+
+    // writes the edits as a halfdiff, with the comments before the markers
+    fn write_edits(
+        &mut self,
+        edits: &Edits,
+        new_files: &[&str],
+        comments: &HashMap<(usize, usize, usize), String>,
+    ) -> Result<Vec<u8>> {
+        let index = self.index(new_files);
+        let mut acc = String::new();
+        let mut notes = Vec::new();
+        let mut prev_id = usize::MAX;
+        for (&key, (lines, no_eol)) in edits {
+            let filename = index[&key.0];
+            if prev_id != key.0 && !self.flat {
+                writeln!(&mut acc, "{} {}", self.header_marker, filename)?;
+                prev_id = key.0;
+            }
+            for line in comments.get(&key).into_iter().flat_map(|x| x.lines()) {
+                let note = format!("{} {}", self.error_marker, line);
+                writeln!(&mut acc, "{note}")?;
+                notes.push(note);
+            }
+            acc.push_str(&self.format_hunk(filename, key, lines, *no_eol, &[])?);
+        }
+        self.notes = notes;
+        Ok(acc.into_bytes())
+    }
+
+    fn parse_edits<'a>(&self, diff: &'a str) -> Result<(Edits, Vec<&'a str>)> {
+        let mut edits = Edits::new();
+        let mut lines = LineAccumulator::new(self);
+
+        let all: Vec<_> = diff.split_terminator('\n').collect();
+        let notes = self.error_notes(&all);
+        for (i, &l) in all.iter().enumerate() {
+            if notes[i] {
+                // errors from the previous round
+                continue;
+            } else if l.starts_with(&self.header_marker) {
+                lines.dump_hunk(&mut edits);
+                lines.open_file(i + 1, l[self.header_marker.len()..].trim());
             } else if l.starts_with(&self.hunk_marker) {
-                lines.dump_hunk(&mut hunks)?;
-                lines.open_new_hunk(l[self.hunk_marker.len()..].trim());
+                lines.dump_hunk(&mut edits);
+                lines.open_new_hunk(i + 1, self.strip_annotation(l));
+            } else if lines.is_empty() {
+                lines.push_stray(i + 1, l);
+            } else if !self.context.is_empty() && l.starts_with(&self.context_marker) {
+                let line = &l[self.context_marker.len()..];
+                lines.push_context(line.strip_prefix(' ').unwrap_or(line));
             } else {
                 lines.push_line(l);
             }
         }
-        lines.dump_hunk(&mut hunks)?;
+        lines.dump_hunk(&mut edits);
+
+        // all the problems are reported at once
+        if !lines.diagnostics.is_empty() {
+            return Err(Diagnostics(lines.diagnostics).into());
+        }
+        Ok((edits, lines.new_files))
+    }
+
+    // keeps the content of the files for three-way merges, unless they have been changed since
+    // the hunks were collected
+    pub fn take_snapshots(&mut self) -> Result<()> {
+        for (path, &id) in &self.files {
+            let Some(hash) = self.hashes[id] else {
+                continue;
+            };
+            let content = std::fs::read(path)
+                .with_context(|| format!("failed to read {path:?}. aborting."))?;
+            if hash_content(&content) == hash {
+                self.snapshots.insert(id, content);
+            }
+        }
+        Ok(())
+    }
+
+    // saves what is needed to parse the edit result later, without the hunks collected again
+    pub fn to_snapshot(&self) -> Value {
+        let mut files = vec![""; self.files.len()];
+        for (path, &id) in &self.files {
+            files[id] = path.as_str();
+        }
+        let crlf: Vec<_> = self
+            .line_endings
+            .iter()
+            .map(|x| *x == LineEnding::Crlf)
+            .collect();
+        let mut snapshots: Vec<_> = self.snapshots.iter().collect();
+        snapshots.sort();
+
+        json!({
+            "version": SNAPSHOT_VERSION,
+            "markers": [self.header_marker, self.hunk_marker, self.context_marker, self.error_marker],
+            "flat": self.flat,
+            "files": files,
+            "crlf": crlf,
+            "hashes": self.hashes,
+            "hunks": key_entries(&self.raw_hunks),
+            "prefill": key_entries(&self.prefill),
+            "groups": key_entries(&self.groups),
+            "eof_newline": key_entries(&self.eof_newline),
+            "context": key_entries(&self.context),
+            "notes": self.notes,
+            "snapshots": snapshots,
+        })
+    }
+
+    pub fn from_snapshot(snapshot: &Value) -> Result<Self> {
+        if snapshot["version"] != SNAPSHOT_VERSION {
+            return Err(anyhow!(
+                "got an unsupported version of the snapshot. aborting."
+            ));
+        }
+        let field = |name: &str| snapshot[name].clone();
+        let invalid = |name: &str| format!("got an invalid {name:?} in the snapshot. aborting.");
+
+        let [header_marker, hunk_marker, context_marker, error_marker]: [String; 4] =
+            serde_json::from_value(field("markers")).with_context(|| invalid("markers"))?;
+        let flat: bool = serde_json::from_value(field("flat")).with_context(|| invalid("flat"))?;
+        let files: Vec<String> =
+            serde_json::from_value(field("files")).with_context(|| invalid("files"))?;
+        let crlf: Vec<bool> =
+            serde_json::from_value(field("crlf")).with_context(|| invalid("crlf"))?;
+        let hashes: Vec<Option<u64>> =
+            serde_json::from_value(field("hashes")).with_context(|| invalid("hashes"))?;
+        let hunks: KeyEntries<Vec<String>> =
+            serde_json::from_value(field("hunks")).with_context(|| invalid("hunks"))?;
+        let prefill: KeyEntries<Vec<String>> =
+            serde_json::from_value(field("prefill")).with_context(|| invalid("prefill"))?;
+        let groups: KeyEntries<Vec<(usize, usize)>> =
+            serde_json::from_value(field("groups")).with_context(|| invalid("groups"))?;
+        let eof_newline: KeyEntries<bool> =
+            serde_json::from_value(field("eof_newline")).with_context(|| invalid("eof_newline"))?;
+        let context: KeyEntries<(Vec<String>, Vec<String>)> =
+            serde_json::from_value(field("context")).with_context(|| invalid("context"))?;
+        let notes: Vec<String> =
+            serde_json::from_value(field("notes")).with_context(|| invalid("notes"))?;
+        let snapshots: Vec<(usize, Vec<u8>)> =
+            serde_json::from_value(field("snapshots")).with_context(|| invalid("snapshots"))?;
+
+        // every key refers to a hunk, and every hunk to a file
+        let keys: HashSet<_> = hunks.iter().map(|x| (x.0, x.1)).collect();
+        let mut referred = prefill
+            .iter()
+            .map(|x| (x.0, x.1))
+            .chain(eof_newline.iter().map(|x| (x.0, x.1)))
+            .chain(context.iter().map(|x| (x.0, x.1)))
+            .chain(groups.iter().flat_map(|x| x.2.iter().copied()));
+        if crlf.len() != files.len()
+            || hashes.len() != files.len()
+            || files.iter().collect::<HashSet<_>>().len() != files.len()
+            || hunks.iter().any(|x| x.0 >= files.len())
+            || !referred.all(|x| keys.contains(&x))
+            || groups.iter().any(|x| x.2.first() != Some(&(x.0, x.1)))
+            || snapshots.iter().any(|x| x.0 >= files.len())
+        {
+            return Err(anyhow!("got an inconsistent snapshot. aborting."));
+        }
+
+        Ok(PatchBuilder {
+            header_marker,
+            hunk_marker,
+            context_marker,
+            error_marker,
+            header_collision_avoidance: false,
+            hunk_collision_avoidance: false,
+            files: files.into_iter().enumerate().map(|(x, y)| (y, x)).collect(),
+            raw_hunks: from_key_entries(hunks),
+            prefill: from_key_entries(prefill),
+            groups: from_key_entries(groups),
+            line_endings: crlf
+                .into_iter()
+                .map(|x| if x { LineEnding::Crlf } else { LineEnding::Lf })
+                .collect(),
+            hashes,
+            rebased: HashMap::new(),
+            rebased_files: HashSet::new(),
+            snapshots: snapshots.into_iter().collect(),
+            eof_newline: from_key_entries(eof_newline),
+            context: from_key_entries(context),
+            annotations: HashMap::new(),
+            notes,
+            sort: SortOrder::default(),
+            flat,
+        })
+    }
+
+    // merges the edits into the current files with the snapshots as the base, leaving conflict
+    // markers in the files. nothing is written unless all the files can be merged
+    pub fn merge_halfdiff(&self, git: &Git, buf: &[u8]) -> Result<()> {
+        let diff = std::str::from_utf8(buf)
+            .context("failed parse the edit result as a UTF-8 string. aborting.")?;
+        let (edits, new_files) = self.parse_edits(diff)?;
+        let index: HashMap<usize, &str> = self.files.iter().map(|x| (*x.1, x.0.as_str())).collect();
+
+        let mut by_file: BTreeMap<usize, Vec<_>> = BTreeMap::new();
+        for (&(id, pos, len), (lines, no_eol)) in &edits {
+            by_file
+                .entry(id)
+                .or_default()
+                .push((pos, len, lines.as_slice(), *no_eol));
+        }
+
+        let mut merged = Vec::new();
+        let mut conflicts = Vec::new();
+        for (id, edits) in by_file {
+            // new files have nothing to merge with
+            if id >= self.files.len() {
+                let path = new_files[id - self.files.len()];
+                if std::path::Path::new(path).exists() {
+                    return Err(anyhow!(
+                        "failed to create {path:?}, which already exists. aborting."
+                    ));
+                }
+                merged.push((path, Self::apply_edits(&[], &edits)));
+                continue;
+            }
+
+            let path = index[&id];
+            let base = self.snapshots.get(&id).with_context(|| {
+                format!(
+                    "failed to find the snapshot of {path:?} for the three-way merge. aborting."
+                )
+            })?;
+            let (content, n_conflicts) =
+                git.merge_file(path, base, &Self::apply_edits(base, &edits))?;
+            if n_conflicts > 0 {
+                conflicts.extend(Self::locate_conflicts(path, &content, &edits));
+            }
+            merged.push((path, content));
+        }
+
+        for (path, content) in merged {
+            if let Some(dir) = std::path::Path::new(path).parent() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("failed to create {dir:?}. aborting."))?;
+            }
+            std::fs::write(path, content)
+                .with_context(|| format!("failed to write {path:?}. aborting."))?;
+        }
+        if !conflicts.is_empty() {
+            return Err(Conflicts(conflicts).into());
+        }
+        Ok(())
+    }
+
+    // replaces the original lines in the content with the edited ones
+    fn apply_edits(content: &[u8], edits: &[(usize, usize, &[String], bool)]) -> Vec<u8> {
+        let eol = LineEnding::detect(content).as_str().as_bytes();
+        let lines: Vec<_> = content.split_inclusive(|&x| x == b'\n').collect();
+
+        let mut acc = Vec::new();
+        let mut i = 0;
+        for &(pos, len, edited, no_eol) in edits {
+            let pos = pos.clamp(i, lines.len());
+            acc.extend(lines[i..pos].concat());
+            for (k, line) in edited.iter().enumerate() {
+                acc.extend_from_slice(line.as_bytes());
+                if !no_eol || k + 1 < edited.len() {
+                    acc.extend_from_slice(eol);
+                }
+            }
+            i = (pos + len).min(lines.len());
+        }
+        acc.extend(lines[i..].concat());
+        acc
+    }
+
+    // tells the hunks whose edited lines are in the conflicting part of each conflict
+    fn locate_conflicts(
+        path: &str,
+        merged: &[u8],
+        edits: &[(usize, usize, &[String], bool)],
+    ) -> Vec<String> {
+        let line_ending = LineEnding::detect(merged);
+        let lines: Vec<_> = merged
+            .split_inclusive(|&x| x == b'\n')
+            .map(|x| String::from_utf8_lossy(line_ending.strip(x)))
+            .collect();
+
+        let mut acc = Vec::new();
+        let mut i = 0;
+        while i < lines.len() {
+            if !lines[i].starts_with("<<<<<<< ") {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            let sep = (start..lines.len()).find(|&x| lines[x] == "=======");
+            let end = (start..lines.len()).find(|&x| lines[x].starts_with(">>>>>>> "));
+            let (Some(sep), Some(end)) = (sep, end) else {
+                break;
+            };
+
+            let edited_part = &lines[sep + 1..end];
+            let hunks: Vec<_> = edits
+                .iter()
+                .filter(|(_, _, edited, _)| {
+                    !edited.is_empty()
+                        && edited_part
+                            .windows(edited.len())
+                            .any(|x| x.iter().zip(edited.iter()).all(|(x, y)| x == y))
+                })
+                .map(|(pos, _, _, _)| (pos + 1).to_string())
+                .collect();
+            acc.push(if hunks.is_empty() {
+                format!("{path}:{}", start + 1)
+            } else {
+                format!(
+                    "{path}:{}: edited in the hunk(s) at {}",
+                    start + 1,
+                    hunks.join(", ")
+                )
+            });
+            i = end + 1;
+        }
+        acc
+    }
+
+    fn changed_files(&self, ids: &[usize]) -> Vec<String> {
+        let index: HashMap<usize, &str> = self.files.iter().map(|x| (*x.1, x.0.as_str())).collect();
+
+        // files not read at collection are not checked
+        let mut changed = Vec::new();
+        for &id in ids {
+            let Some(hash) = self.hashes.get(id).copied().flatten() else {
+                continue;
+            };
+            let path = index[&id];
+            if std::fs::read(path).map_or(true, |x| hash_content(&x) != hash) {
+                changed.push(path.to_string());
+            }
+        }
+        changed
+    }
+
+    // moves the hunks of the changed files to the nearest occurrences of their original lines, so
+    // that the edits are applied to the current content
+    pub fn rebase(&mut self) -> Result<()> {
+        let ids: Vec<_> = (0..self.hashes.len()).collect();
+        for path in self.changed_files(&ids) {
+            let id = self.files[&path];
+            let content = std::fs::read(&path)
+                .with_context(|| format!("failed to read {path:?}. aborting."))?;
+            let line_ending = LineEnding::detect(&content);
+            let lines: Vec<_> = content
+                .split_inclusive(|&x| x == b'\n')
+                .map(|x| String::from_utf8_lossy(line_ending.strip(x)))
+                .collect();
+
+            let mut keys: Vec<_> = self
+                .raw_hunks
+                .keys()
+                .filter(|x| x.0 == id)
+                .copied()
+                .collect();
+            keys.sort();
+
+            let mut end = 0;
+            for key in keys {
+                let original = &self.raw_hunks[&key];
+                let pos = (0..=lines.len().saturating_sub(original.len()))
+                    .filter(|&x| lines.get(x..x + original.len()).is_some_and(|x| x == original))
+                    .min_by_key(|&x| x.abs_diff(key.1))
+                    .with_context(|| {
+                        format!(
+                            "failed to find the original lines of the hunk at {path}:{} in the current content. aborting.",
+                            key.1 + 1
+                        )
+                    })?;
+                if pos < end {
+                    return Err(anyhow!(
+                        "failed to rebase the hunk at {path}:{}, as the hunks have been reordered. aborting.",
+                        key.1 + 1
+                    ));
+                }
+                end = pos + original.len();
+                if self.eof_newline.contains_key(&key) && end != lines.len() {
+                    return Err(anyhow!(
+                        "failed to rebase the hunk at {path}:{}, as it no longer ends the file. aborting.",
+                        key.1 + 1
+                    ));
+                }
+                self.rebased.insert(key, pos);
+            }
+
+            self.hashes[id] = Some(hash_content(&content));
+            self.rebased_files.insert(id);
+        }
+        Ok(())
+    }
+
+    fn build_patch(&self, edits: &Edits, new_files: &[&str]) -> Result<String> {
+        let index: HashMap<usize, &str> = self.files.iter().map(|x| (*x.1, x.0.as_str())).collect();
+
+        // hunks in rebased files are moved to the current locations. lines inserted by the user
+        // have no original lines to find there
+        let mut relocated = Vec::new();
+        for (&(id, pos, len), edit) in edits {
+            let current = match self.rebased.get(&(id, pos)) {
+                Some(&x) if len > 0 => x,
+                _ if len == 0 && self.rebased_files.contains(&id) => {
+                    return Err(anyhow!(
+                        "failed to rebase the lines inserted at {}:{}. aborting.",
+                        index[&id],
+                        pos + 1
+                    ));
+                }
+                _ => pos,
+            };
+            relocated.push(((id, current, len), pos, edit));
+        }
+        relocated.sort_by_key(|x| x.0);
+
+        // hunks are dumped in the order of the locations, so that the offsets are correct
+        // regardless of the order in the edit result
+        let mut patch = String::new();
+        let mut hunks = HunkAccumulator::new();
+
+        let mut prev_id = usize::MAX;
+        for ((id, current, len), pos, (edited_lines, edited_no_eol)) in relocated {
+            let created = id >= self.files.len();
+            if prev_id != id {
+                hunks.dump_patch(&mut patch);
+                if created {
+                    hunks.open_new_patch(new_files[id - self.files.len()], LineEnding::Lf, true)
+                } else {
+                    hunks.open_new_patch(index[&id], self.line_endings[id], false)
+                }
+                prev_id = id;
+            }
+
+            let original_lines: &[String] = match len {
+                0 => &[],
+                _ => &self.raw_hunks[&(id, pos)],
+            };
+            let original_no_eol = len > 0 && self.eof_newline.get(&(id, pos)) == Some(&false);
+            hunks.push_hunk(
+                current,
+                (original_lines, original_no_eol),
+                (edited_lines, *edited_no_eol),
+            )?;
+        }
         hunks.dump_patch(&mut patch);
 
         Ok(patch)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::hunks::{Hunks, LineEnding, hash_content};
+    use crate::patch::{Changed, Diagnostics, Edits, HalfDiffConfig, PatchBuilder, SortOrder};
+    use std::collections::HashMap;
+
+    fn builder(config: &HalfDiffConfig, hunks: Vec<(usize, usize, Vec<&str>)>) -> PatchBuilder {
+        let hunks = Hunks {
+            files: vec!["a.txt".to_string(), "b.txt".to_string()],
+            hunks: hunks
+                .into_iter()
+                .map(|(id, pos, lines)| (id, pos, lines.iter().map(|x| x.to_string()).collect()))
+                .collect(),
+            prefill: HashMap::new(),
+            line_endings: vec![LineEnding::Lf; 2],
+            hashes: vec![None; 2],
+            eof_newline: HashMap::new(),
+            context: HashMap::new(),
+            annotations: HashMap::new(),
+        };
+        PatchBuilder::from_hunks(config, hunks).unwrap()
+    }
+
+    #[test]
+    fn test_parse_halfdiff() {
+        let config = HalfDiffConfig::default();
+        let builder = builder(
+            &config,
+            vec![
+                (0, 1, vec!["x"]),
+                (0, 2, vec!["x"]),
+                (0, 3, vec!["x", "y"]),
+                (1, 0, vec!["z"]),
+            ],
+        );
+
+        let mut buf = Vec::new();
+        builder.write_halfdiff(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "+++ a.txt\n@@ 2,1\nx\n@@ 3,1\nx\n@@ 4,2\nx\ny\n+++ b.txt\n@@ 1,1\nz\n"
+        );
+
+        // unchanged
+        let patch = builder.parse_halfdiff(&buf).unwrap();
+        assert_eq!(patch, "");
+
+        // replace, remove, and expand
+        let patch = builder
+            .parse_halfdiff(b"+++ a.txt\n@@ 2,1\nx\n@@ 3,1\nX\n@@ 4,2\n+++ b.txt\n@@ 1,1\nz\nw\n")
+            .unwrap();
+        assert_eq!(
+            patch,
+            concat!(
+                "--- a/a.txt\n+++ b/a.txt\n",
+                "@@ -3,1 +3,1 @@\n-x\n+X\n",
+                "@@ -4,2 +3,0 @@\n-x\n-y\n",
+                "--- a/b.txt\n+++ b/b.txt\n",
+                "@@ -1,1 +1,2 @@\n-z\n+z\n+w\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_group_identical() {
+        let config = HalfDiffConfig {
+            group_identical: true,
+            ..Default::default()
+        };
+        let builder = builder(
+            &config,
+            vec![
+                (0, 1, vec!["x"]),
+                (0, 2, vec!["x"]),
+                (0, 3, vec!["x", "y"]),
+                (1, 0, vec!["x"]),
+                (1, 4, vec!["z"]),
+            ],
+        );
+
+        let mut buf = Vec::new();
+        builder.write_halfdiff(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            concat!(
+                "+++ a.txt\n",
+                "@@ 2,1 @@ also at a.txt:3, b.txt:1\n",
+                "x\n",
+                "@@ 4,2\n",
+                "x\n",
+                "y\n",
+                "+++ b.txt\n",
+                "@@ 5,1\n",
+                "z\n",
+            )
+        );
+        assert_eq!(builder.parse_halfdiff(&buf).unwrap(), "");
+
+        // the edit is replayed onto every member, with the offsets computed per file
+        let edited = "+++ a.txt\n@@ 2,1 @@ also at a.txt:3, b.txt:1\nX\nW\n@@ 4,2\nx\ny\n+++ b.txt\n@@ 5,1\nZ\n";
+        assert_eq!(
+            builder.parse_halfdiff(edited.as_bytes()).unwrap(),
+            concat!(
+                "--- a/a.txt\n+++ b/a.txt\n",
+                "@@ -2,1 +2,2 @@\n-x\n+X\n+W\n",
+                "@@ -3,1 +4,2 @@\n-x\n+X\n+W\n",
+                "--- a/b.txt\n+++ b/b.txt\n",
+                "@@ -1,1 +1,2 @@\n-x\n+X\n+W\n",
+                "@@ -5,1 +6,1 @@\n-z\n+Z\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_sort() {
+        let hunks = vec![
+            (0, 0, vec!["let x = 1;"]),
+            (0, 5, vec!["fn main() {"]),
+            (1, 0, vec!["let y = 1;"]),
+            (1, 3, vec!["fn main() -> Result<()> {"]),
+        ];
+
+        let config = HalfDiffConfig {
+            sort: SortOrder::Content,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        builder(&config, hunks.clone())
+            .write_halfdiff(&mut buf)
+            .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            concat!(
+                "+++ b.txt\n@@ 4,1\nfn main() -> Result<()> {\n",
+                "+++ a.txt\n@@ 6,1\nfn main() {\n@@ 1,1\nlet x = 1;\n",
+                "+++ b.txt\n@@ 1,1\nlet y = 1;\n",
+            )
+        );
+
+        let config = HalfDiffConfig {
+            sort: SortOrder::Similarity,
+            ..Default::default()
+        };
+        let builder = builder(&config, hunks);
+        let mut buf = Vec::new();
+        builder.write_halfdiff(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            concat!(
+                "+++ a.txt\n@@ 1,1\nlet x = 1;\n",
+                "+++ b.txt\n@@ 1,1\nlet y = 1;\n",
+                "+++ a.txt\n@@ 6,1\nfn main() {\n",
+                "+++ b.txt\n@@ 4,1\nfn main() -> Result<()> {\n",
+            )
+        );
+
+        // the patch follows the original locations regardless of the order in the buffer
+        let edited = concat!(
+            "+++ a.txt\n@@ 1,1\nlet x = 2;\n",
+            "+++ b.txt\n@@ 1,1\nlet y = 1;\n",
+            "+++ a.txt\n@@ 6,1\nfn main() {\n",
+            "+++ b.txt\n@@ 4,1\nfn main() {\n",
+        );
+        assert_eq!(
+            builder.parse_halfdiff(edited.as_bytes()).unwrap(),
+            concat!(
+                "--- a/a.txt\n+++ b/a.txt\n",
+                "@@ -1,1 +1,1 @@\n-let x = 1;\n+let x = 2;\n",
+                "--- a/b.txt\n+++ b/b.txt\n",
+                "@@ -4,1 +4,1 @@\n-fn main() -> Result<()> {\n+fn main() {\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_flat() {
+        let config = HalfDiffConfig {
+            flat: true,
+            ..Default::default()
+        };
+        let builder = builder(
+            &config,
+            vec![(0, 0, vec!["x"]), (0, 2, vec!["y"]), (1, 1, vec!["z"])],
+        );
+        let mut buf = Vec::new();
+        builder.write_halfdiff(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "@@ a.txt:1,1\nx\n@@ a.txt:3,1\ny\n@@ b.txt:2,1\nz\n"
+        );
+
+        // hunks can be moved across files, and mixed with headers
+        let edited = "@@ b.txt:2,1\nZ\n@@ a.txt:3,1\nY\n@@ 1,0\nw\n+++ b.txt\n@@ a.txt:1,1\nX\n";
+        assert_eq!(
+            builder.parse_halfdiff(edited.as_bytes()).unwrap(),
+            concat!(
+                "--- a/a.txt\n+++ b/a.txt\n",
+                "@@ -0,0 +1,1 @@\n+w\n@@ -1,1 +2,1 @@\n-x\n+X\n@@ -3,1 +4,1 @@\n-y\n+Y\n",
+                "--- a/b.txt\n+++ b/b.txt\n@@ -2,1 +2,1 @@\n-z\n+Z\n",
+            )
+        );
+
+        // the path is taken only in the flat format
+        let config = HalfDiffConfig::default();
+        let plain = super::tests::builder(&config, vec![(0, 0, vec!["x"]), (1, 1, vec!["z"])]);
+        assert!(
+            plain
+                .parse_halfdiff(b"+++ a.txt\n@@ b.txt:2,1\nZ\n")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let config = HalfDiffConfig::default();
+        let mut builder = builder(&config, vec![(0, 0, vec!["x"]), (1, 0, vec!["y"])]);
+        builder.line_endings[1] = LineEnding::Crlf;
+
+        // the halfdiff is always LF, while the patch lines follow the files
+        let mut buf = Vec::new();
+        builder.write_halfdiff(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "+++ a.txt\n@@ 1,1\nx\n+++ b.txt\n@@ 1,1\ny\n"
+        );
+        assert_eq!(
+            builder
+                .parse_halfdiff(b"+++ a.txt\n@@ 1,1\nX\n+++ b.txt\n@@ 1,1\r\nY\r\n")
+                .unwrap(),
+            concat!(
+                "--- a/a.txt\n+++ b/a.txt\n",
+                "@@ -1,1 +1,1 @@\n-x\n+X\n",
+                "--- a/b.txt\n+++ b/b.txt\n",
+                "@@ -1,1 +1,1 @@\n-y\r\n+Y\r\n",
+            )
+        );
+
+        // a stray CR in an LF file is content, which is kept unless edited
+        let builder = super::tests::builder(&config, vec![(0, 0, vec!["x", "y\r"])]);
+        let mut buf = Vec::new();
+        builder.write_halfdiff(&mut buf).unwrap();
+        assert_eq!(builder.parse_halfdiff(&buf).unwrap(), "");
+        assert_eq!(
+            builder
+                .parse_halfdiff(b"+++ a.txt\n@@ 1,2\nX\ny\r\n")
+                .unwrap(),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +1,2 @@\n-x\n-y\r\n+X\n+y\r\n"
+        );
+    }
+
+    #[test]
+    fn test_eof_newline() {
+        let config = HalfDiffConfig::default();
+        let mut builder = builder(
+            &config,
+            vec![(0, 1, vec!["w"]), (0, 3, vec!["x"]), (1, 0, vec!["y", "z"])],
+        );
+        builder.eof_newline = HashMap::from([((0, 3), true), ((1, 0), false)]);
+        builder.line_endings[1] = LineEnding::Crlf;
+
+        let mut buf = Vec::new();
+        builder.write_halfdiff(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "+++ a.txt\n@@ 2,1\nw\n@@ 4,1\nx\n+++ b.txt\n@@ 1,2\ny\nz\n\\ No newline at end of file\n"
+        );
+        assert_eq!(builder.parse_halfdiff(&buf).unwrap(), "");
+
+        // the final newline is removed from a.txt by adding the marker, and added to b.txt by
+        // removing it
+        let edited =
+            "+++ a.txt\n@@ 4,1\nx\n\\ No newline at end of file\n+++ b.txt\n@@ 1,2\ny\nZ\n";
+        assert_eq!(
+            builder.parse_halfdiff(edited.as_bytes()).unwrap(),
+            concat!(
+                "--- a/a.txt\n+++ b/a.txt\n",
+                "@@ -4,1 +4,1 @@\n-x\n+x\n\\ No newline at end of file\n",
+                "--- a/b.txt\n+++ b/b.txt\n",
+                "@@ -1,2 +1,2 @@\n-y\r\n-z\n\\ No newline at end of file\n+y\r\n+Z\r\n",
+            )
+        );
+
+        // hunks in the middle of files can't lose the newline
+        let edited = "+++ a.txt\n@@ 2,1\nw\n\\ No newline at end of file\n";
+        assert!(builder.parse_halfdiff(edited.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_new_file() {
+        let config = HalfDiffConfig::default();
+        let builder = builder(&config, vec![(0, 0, vec!["x"])]);
+
+        // paths that don't exist are created from "1,0" hunks, after the edits to existing files
+        let edited = concat!(
+            "+++ new/c.txt\n@@ 1,0\nc\nd\n\\ No newline at end of file\n",
+            "+++ a.txt\n@@ 1,1\nX\n",
+            "+++ new/d.txt\n@@ 1,0\n",
+        );
+        assert_eq!(
+            builder.parse_halfdiff(edited.as_bytes()).unwrap(),
+            concat!(
+                "--- a/a.txt\n+++ b/a.txt\n@@ -1,1 +1,1 @@\n-x\n+X\n",
+                "--- /dev/null\n+++ b/new/c.txt\n@@ -0,0 +1,2 @@\n+c\n+d\n\\ No newline at end of file\n",
+            )
+        );
+
+        // other hunks, and existing files without hits, are rejected
+        let edited = "+++ new/c.txt\n@@ 2,0\nc\n";
+        assert!(builder.parse_halfdiff(edited.as_bytes()).is_err());
+        let edited = "+++ Cargo.toml\n@@ 1,0\nc\n";
+        assert!(builder.parse_halfdiff(edited.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_insertion() {
+        let config = HalfDiffConfig::default();
+        let builder = builder(&config, vec![(0, 1, vec!["x", "y"])]);
+
+        // insertions are placed before the hunk at the same position, regardless of the order
+        let edited = "+++ a.txt\n@@ 5,0\nt\n@@ 2,2\nX\ny\n@@ 1,0\nh\ni\n@@ 2,0\nm\n@@ 9,0\n";
+        assert_eq!(
+            builder.parse_halfdiff(edited.as_bytes()).unwrap(),
+            concat!(
+                "--- a/a.txt\n+++ b/a.txt\n",
+                "@@ -0,0 +1,2 @@\n+h\n+i\n",
+                "@@ -1,0 +4,1 @@\n+m\n",
+                "@@ -2,2 +5,2 @@\n-x\n-y\n+X\n+y\n",
+                "@@ -4,0 +8,1 @@\n+t\n",
+            )
+        );
+
+        // lines can't be inserted into a hunk, nor without the newline, and other hunks are rejected
+        for edited in [
+            "+++ a.txt\n@@ 3,0\nz\n",
+            "+++ a.txt\n@@ 7,0\nz\n\\ No newline at end of file\n",
+            "+++ a.txt\n@@ 2,1\nX\n",
+            "+++ a.txt\n@@ 2\nX\n",
+        ] {
+            assert!(builder.parse_halfdiff(edited.as_bytes()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let config = HalfDiffConfig::default();
+        let builder = builder(
+            &config,
+            vec![(0, 1, vec!["x"]), (0, 4, vec!["y"]), (1, 0, vec!["w"])],
+        );
+
+        // every problem is reported with its line, and lines in broken hunks are ignored
+        let edited = concat!(
+            "@@ 1,1\nignored\n",
+            "+++ a.txt\nstray\n\n@@ 2,1\nX\n@@ 2,1\nY\n@@ 3,x\nignored\n@@ 5,2\n",
+            "+++ Cargo.toml\n@@ 1,1\nignored\n",
+            "+++ b.txt\n@@ 1,1\nw\n\\ No newline at end of file\n",
+        );
+        let err = builder.parse_halfdiff(edited.as_bytes()).unwrap_err();
+        let lines: Vec<_> = err
+            .downcast_ref::<Diagnostics>()
+            .unwrap()
+            .0
+            .iter()
+            .map(|x| x.0)
+            .collect();
+        assert_eq!(lines, vec![1, 4, 8, 10, 12, 13, 17]);
+    }
+
+    #[test]
+    fn test_annotate_errors() {
+        let config = HalfDiffConfig::default();
+        let mut builder = builder(&config, vec![(0, 1, vec!["x"]), (1, 3, vec!["y"])]);
+
+        // errors are placed before the offending lines
+        let edited = "+++ a.txt\n@@ 2,7\nX\n+++ b.txt\n@@ 4,1\nY\n";
+        let err = builder.parse_halfdiff(edited.as_bytes()).unwrap_err();
+        let annotated = builder.annotate_errors(edited.as_bytes(), &err);
+        assert_eq!(
+            std::str::from_utf8(&annotated).unwrap(),
+            concat!(
+                "+++ a.txt\n",
+                "!! got a hunk marker \"2,7\" that doesn't match any original hunk; markers must not be edited\n",
+                "@@ 2,7\nX\n+++ b.txt\n@@ 4,1\nY\n",
+            )
+        );
+
+        // errors from "git apply" are placed at the failed hunk, replacing the previous ones
+        let edited = String::from_utf8(annotated)
+            .unwrap()
+            .replace("@@ 2,7", "@@ 2,1");
+        let err =
+            anyhow::anyhow!("error: patch failed: b.txt:4\nerror: b.txt: patch does not apply")
+                .context("\"git apply\" returned an error. aborting.");
+        let annotated = builder.annotate_errors(edited.as_bytes(), &err);
+        assert_eq!(
+            std::str::from_utf8(&annotated).unwrap(),
+            concat!(
+                "!! \"git apply\" returned an error. aborting.\n",
+                "!! error: b.txt: patch does not apply\n",
+                "+++ a.txt\n@@ 2,1\nX\n+++ b.txt\n",
+                "!! error: patch failed: b.txt:4\n",
+                "@@ 4,1\nY\n",
+            )
+        );
+        assert!(builder.parse_halfdiff(&annotated).is_ok());
+
+        // the other lines with the error marker are content, even at the end of a hunk
+        let edited = std::str::from_utf8(&annotated).unwrap().replace(
+            "@@ 2,1\nX\n",
+            "@@ 2,1\n!! X\nX\n!! error: b.txt: patch does not apply\n",
+        ) + "!! keep me\n";
+        let patch = builder.parse_halfdiff(edited.as_bytes()).unwrap();
+        assert!(
+            patch.contains(
+                "@@ -2,1 +2,3 @@\n-x\n+!! X\n+X\n+!! error: b.txt: patch does not apply\n"
+            )
+        );
+        assert!(patch.contains("@@ -4,1 +4,2 @@\n-y\n+Y\n+!! keep me\n"));
+        assert!(!patch.contains("git apply"));
+        let err = anyhow::anyhow!("error: patch failed: b.txt:4");
+        let annotated = builder.annotate_errors(edited.as_bytes(), &err);
+        assert_eq!(
+            std::str::from_utf8(&annotated).unwrap(),
+            concat!(
+                "+++ a.txt\n@@ 2,1\n!! X\nX\n!! error: b.txt: patch does not apply\n",
+                "+++ b.txt\n!! error: patch failed: b.txt:4\n@@ 4,1\nY\n!! keep me\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_rebase() {
+        let path = std::env::temp_dir().join(format!("ge-rebase-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        std::fs::write(&path, "a\nx\nb\ny\n").unwrap();
+
+        let config = HalfDiffConfig::default();
+        let mut builder = builder(&config, vec![(0, 1, vec!["x"]), (0, 3, vec!["y"])]);
+        builder.files = HashMap::from([(path.clone(), 0)]);
+        builder.hashes = vec![Some(hash_content(b"a\nx\nb\ny\n")), None];
+
+        let edited = format!("+++ {path}\n@@ 2,1\nX\n@@ 4,1\nY\n");
+        let patch = |x: &str| format!("--- a/{path}\n+++ b/{path}\n{x}");
+        assert_eq!(
+            builder.parse_halfdiff(edited.as_bytes()).unwrap(),
+            patch("@@ -2,1 +2,1 @@\n-x\n+X\n@@ -4,1 +4,1 @@\n-y\n+Y\n")
+        );
+
+        // changes are detected, and the hunks are moved to the current locations by rebasing
+        std::fs::write(&path, "y\na\nx\nb\nb\ny\n").unwrap();
+        let err = builder.parse_halfdiff(edited.as_bytes()).unwrap_err();
+        assert_eq!(err.downcast_ref::<Changed>().unwrap().0, vec![path.clone()]);
+        builder.rebase().unwrap();
+        assert_eq!(
+            builder.parse_halfdiff(edited.as_bytes()).unwrap(),
+            patch("@@ -3,1 +3,1 @@\n-x\n+X\n@@ -6,1 +6,1 @@\n-y\n+Y\n")
+        );
+
+        // lines inserted by the user can't be rebased, nor hunks whose lines are gone
+        let inserted = format!("{edited}@@ 1,0\nz\n");
+        assert!(builder.parse_halfdiff(inserted.as_bytes()).is_err());
+        std::fs::write(&path, "a\nb\ny\n").unwrap();
+        assert!(builder.rebase().is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_three_way() {
+        let lines = |x: &[&str]| -> Vec<String> { x.iter().map(|x| x.to_string()).collect() };
+        let (x, y, z) = (lines(&["X"]), lines(&["Y", "Z"]), lines(&["w"]));
+
+        // the edited content keeps the line endings, and the final newline follows the edits
+        let edits = [
+            (0, 0, z.as_slice(), false),
+            (1, 1, x.as_slice(), false),
+            (3, 1, y.as_slice(), true),
+        ];
+        assert_eq!(
+            PatchBuilder::apply_edits(b"a\r\nx\r\nb\r\ny\r\n", &edits),
+            b"w\r\na\r\nX\r\nb\r\nY\r\nZ"
+        );
+
+        // conflicts are attributed to the hunks by the edited lines
+        let merged = "a\n<<<<<<< current\nx2\n=======\nX\n>>>>>>> edited\nb\n<<<<<<< current\n=======\n>>>>>>> edited\n";
+        assert_eq!(
+            PatchBuilder::locate_conflicts("a.txt", merged.as_bytes(), &edits),
+            vec!["a.txt:2: edited in the hunk(s) at 2", "a.txt:8"]
+        );
+    }
+
+    #[test]
+    fn test_rejects() {
+        let lines = |x: &[&str]| -> Vec<String> { x.iter().map(|x| x.to_string()).collect() };
+        let config = HalfDiffConfig::default();
+        let mut builder = builder(
+            &config,
+            vec![
+                (0, 1, vec!["x"]),
+                (0, 3, vec!["y"]),
+                (0, 5, vec!["z"]),
+                (1, 0, vec!["w"]),
+            ],
+        );
+        builder.context = HashMap::from([((0, 3), (lines(&["b"]), vec![]))]);
+
+        // the hunks left are moved by the lines added before them
+        let applied = Edits::from([((0, 1, 1), (lines(&["X", "X2"]), false))]);
+        let rejected = Edits::from([
+            ((0, 3, 1), (lines(&["Y"]), false)),
+            ((0, 5, 0), (lines(&["v"]), false)),
+        ]);
+        let left: Edits = rejected
+            .into_iter()
+            .map(|(key, edit)| (builder.shifted(&applied, key), edit))
+            .collect();
+        assert_eq!(
+            left.keys().collect::<Vec<_>>(),
+            vec![&(0, 4, 1), &(0, 6, 0)]
+        );
+        builder.settle_applied(&applied).unwrap();
+        let mut keys: Vec<_> = builder.raw_hunks.keys().copied().collect();
+        keys.sort();
+        assert_eq!(keys, vec![(0, 4), (0, 6), (1, 0)]);
+
+        // the fresh halfdiff has the rejected hunks only, with the errors
+        let comments = HashMap::from([((0, 4, 1), "error: patch failed".to_string())]);
+        let buf = builder.write_edits(&left, &[], &comments).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "+++ a.txt\n!! error: patch failed\n@@ 5,1\n:: b\nY\n@@ 7,0\nv\n"
+        );
+        assert_eq!(
+            builder.parse_halfdiff(&buf).unwrap(),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -5,1 +5,1 @@\n-y\n+Y\n@@ -6,0 +7,1 @@\n+v\n"
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let config = HalfDiffConfig {
+            group_identical: true,
+            flat: true,
+            ..Default::default()
+        };
+        let mut builder = builder(
+            &config,
+            vec![(0, 0, vec!["x"]), (0, 2, vec!["y"]), (1, 1, vec!["x"])],
+        );
+        builder.line_endings[1] = LineEnding::Crlf;
+        builder.eof_newline.insert((0, 2), false);
+        builder
+            .context
+            .insert((0, 2), (vec!["w".to_string()], vec![]));
+        builder.notes = vec!["!! error: patch failed".to_string()];
+
+        // the restored builder writes the same halfdiff and parses it into the same patch
+        let snapshot = serde_json::from_str(&builder.to_snapshot().to_string()).unwrap();
+        let restored = PatchBuilder::from_snapshot(&snapshot).unwrap();
+        let (mut buf, mut restored_buf) = (Vec::new(), Vec::new());
+        builder.write_halfdiff(&mut buf).unwrap();
+        restored.write_halfdiff(&mut restored_buf).unwrap();
+        assert_eq!(buf, restored_buf);
+
+        let edited = "!! error: patch failed\n@@ a.txt:1,1\nX\n@@ a.txt:3,1\n:: w\nY\n\\ No newline at end of file\n";
+        let patch = builder.parse_halfdiff(edited.as_bytes()).unwrap();
+        assert_eq!(restored.parse_halfdiff(edited.as_bytes()).unwrap(), patch);
+        assert!(patch.contains("--- a/b.txt\n+++ b/b.txt\n@@ -2,1 +2,1 @@\n-x\r\n+X\r\n"));
+
+        // the hashes don't depend on the build
+        assert_eq!(hash_content(b"x\n"), 0x08f0de07b58d2b17);
+
+        // the contents for three-way merges are kept as they are
+        builder.snapshots.insert(1, b"\xff\r\n".to_vec());
+        let restored = PatchBuilder::from_snapshot(&builder.to_snapshot()).unwrap();
+        assert_eq!(restored.snapshots, builder.snapshots);
+
+        // snapshots of other versions, or with keys of unknown hunks or files, are refused
+        let mut snapshot = builder.to_snapshot();
+        snapshot["version"] = serde_json::json!(0);
+        assert!(PatchBuilder::from_snapshot(&snapshot).is_err());
+        for (name, value) in [
+            ("groups", serde_json::json!([[0, 0, [[0, 0], [0, 1]]]])),
+            ("groups", serde_json::json!([[0, 2, [[0, 0], [0, 2]]]])),
+            ("context", serde_json::json!([[1, 2, [[], []]]])),
+            ("eof_newline", serde_json::json!([[2, 0, false]])),
+            ("prefill", serde_json::json!([[0, 1, ["x"]]])),
+            ("snapshots", serde_json::json!([[2, [120]]])),
+            ("files", serde_json::json!(["a.txt", "a.txt"])),
+        ] {
+            let mut snapshot = builder.to_snapshot();
+            snapshot[name] = value;
+            assert!(PatchBuilder::from_snapshot(&snapshot).is_err());
+        }
+    }
+
+    #[test]
+    fn test_show_context() {
+        let config = HalfDiffConfig::default();
+        let mut builder = builder(&config, vec![(0, 1, vec!["x"]), (0, 3, vec!["::y"])]);
+        builder.context = HashMap::from([
+            ((0, 1), (vec!["a".to_string()], vec!["".to_string()])),
+            ((0, 3), (vec!["".to_string()], vec![])),
+        ]);
+        builder.avoid_collision().unwrap();
+
+        // the marker is extended as a hunk line starts with "::"
+        let mut buf = Vec::new();
+        builder.write_halfdiff(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "+++ a.txt\n@@ 2,1\n::: a\nx\n:::\n@@ 4,1\n:::\n::y\n"
+        );
+        assert_eq!(builder.parse_halfdiff(&buf).unwrap(), "");
+
+        // context lines are ignored, even if deleted
+        let edited = "+++ a.txt\n@@ 2,1\n::: a\nX\n@@ 4,1\n::: \n::y\n";
+        assert_eq!(
+            builder.parse_halfdiff(edited.as_bytes()).unwrap(),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -2,1 +2,1 @@\n-x\n+X\n"
+        );
+
+        // but modified ones are rejected
+        let edited = "+++ a.txt\n@@ 2,1\n::: A\nX\n:::\n@@ 4,1\n:::\n::y\n";
+        assert!(builder.parse_halfdiff(edited.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_annotations() {
+        let config = HalfDiffConfig {
+            group_identical: true,
+            ..Default::default()
+        };
+        let mut builder = builder(&config, vec![(0, 1, vec!["x"]), (1, 0, vec!["x"])]);
+        builder.annotations = HashMap::from([(
+            (0, 1),
+            vec!["fn main() {".to_string(), "alice 2024-05-01".to_string()],
+        )]);
+
+        let mut buf = Vec::new();
+        builder.write_halfdiff(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "+++ a.txt\n@@ 2,1 @@ fn main() { @@ alice 2024-05-01 @@ also at b.txt:1\nx\n"
+        );
+
+        // annotations are ignored, even if edited
+        let edited = "+++ a.txt\n@@ 2,1 @@ fn foo() {\nX\n";
+        assert_eq!(
+            builder.parse_halfdiff(edited.as_bytes()).unwrap(),
+            concat!(
+                "--- a/a.txt\n+++ b/a.txt\n@@ -2,1 +2,1 @@\n-x\n+X\n",
+                "--- a/b.txt\n+++ b/b.txt\n@@ -1,1 +1,1 @@\n-x\n+X\n",
+            )
+        );
+    }
+}
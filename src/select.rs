@@ -0,0 +1,124 @@
+use crate::git::GrepResult;
+use anyhow::{Context, Result, anyhow};
+use std::collections::HashMap;
+use std::io::Write;
+
+pub struct HitList {
+    entries: Vec<String>,
+}
+
+impl HitList {
+    pub fn from_matches(matches: &GrepResult) -> Result<Self> {
+        // we show the first line of each hit
+        let mut contents = HashMap::new();
+        let mut entries = Vec::new();
+
+        for hit in &matches.hits {
+            let filename = matches.files[hit.file_id].as_str();
+            if !contents.contains_key(filename) {
+                let content = std::fs::read_to_string(filename)
+                    .with_context(|| format!("failed to read {filename:?}. aborting."))?;
+                contents.insert(filename, content);
+            }
+            let line = contents[filename].lines().nth(hit.from).unwrap_or("");
+
+            let location = if hit.n_lines > 1 {
+                format!("{}-{}", hit.from + 1, hit.from + hit.n_lines)
+            } else {
+                format!("{}", hit.from + 1)
+            };
+            entries.push(format!("{filename}:{location}: {line}"));
+        }
+
+        Ok(HitList { entries })
+    }
+
+    pub fn write_list(&self, drain: &mut dyn Write) -> Result<()> {
+        for entry in &self.entries {
+            drain.write_all(entry.as_bytes())?;
+            drain.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    pub fn parse_list(&self, matches: &mut GrepResult, buf: &[u8]) -> Result<()> {
+        let list = std::str::from_utf8(buf)
+            .context("failed parse the selection as a UTF-8 string. aborting.")?;
+
+        // entries are compared as a whole, as the filenames and the lines may contain colons
+        let index: HashMap<&str, usize> = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (x.as_str(), i))
+            .collect();
+
+        let mut keep = vec![false; self.entries.len()];
+        for (i, line) in list.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let id = index.get(line).with_context(|| {
+                format!(
+                    "got an unknown entry {line:?} at line {} of the selection (entries can only be deleted). aborting.",
+                    i + 1
+                )
+            })?;
+            keep[*id] = true;
+        }
+
+        if keep.len() != matches.hits.len() {
+            return Err(anyhow!(
+                "the selection is inconsistent with the hits. aborting."
+            ));
+        }
+
+        let mut it = keep.iter();
+        matches.hits.retain(|_| *it.next().unwrap());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::git::{GrepHit, GrepResult};
+    use crate::select::HitList;
+
+    #[test]
+    fn test_select() {
+        let hit = |from, n_lines| GrepHit {
+            file_id: 0,
+            from,
+            n_lines,
+            level: 0,
+        };
+        let mut matches = GrepResult {
+            files: vec!["tests/quick.txt".to_string()],
+            hits: vec![hit(3, 1), hit(9, 1), hit(18, 2)],
+        };
+
+        let list = HitList::from_matches(&matches).unwrap();
+        let mut buf = Vec::new();
+        list.write_list(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            concat!(
+                "tests/quick.txt:4: The quick brown fox\n",
+                "tests/quick.txt:10: The fox jumps\n",
+                "tests/quick.txt:19-20: There is a dog.\n",
+            )
+        );
+
+        // edited entries are rejected
+        assert!(
+            list.parse_list(&mut matches, b"tests/quick.txt:4: The quick brown cat\n")
+                .is_err()
+        );
+
+        let selection =
+            b"tests/quick.txt:19-20: There is a dog.\n\ntests/quick.txt:4: The quick brown fox\n";
+        list.parse_list(&mut matches, selection).unwrap();
+        assert_eq!(matches.hits, vec![hit(3, 1), hit(18, 2)]);
+    }
+}
@@ -0,0 +1,6 @@
+// owl in a comment
+static owl: &'static str = "";
+const C: char = '"'; const D: &str = owl;
+const RAW: &str = r#"owl in a "raw" string"#;
+const E: () = ();
+/* outer /* owl */ still a comment, owl */